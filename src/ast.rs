@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::fmt;
 
 /// Top-level parsed token
 #[derive(Debug, Clone, PartialEq)]
@@ -55,3 +56,32 @@ impl<'a> MacroCall<'a> {
         }
     }
 }
+
+/// Renders tokens back to M4 source text
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::MacroCall(mc) => write!(f, "{}", mc),
+            Token::Positional(n) => write!(f, "${}", n),
+            Token::Literal(s) => f.write_str(s),
+            Token::Group(g) => f.write_str(&g.lexeme),
+        }
+    }
+}
+
+impl fmt::Display for MacroCall<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if !self.args.is_empty() {
+            f.write_str("(")?;
+            for (i, arg) in self.args.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(",")?;
+                }
+                write!(f, "{}", arg)?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,561 @@
+//! Command-line front end compatible with the common subset of GNU m4.
+//!
+//! Input files are processed in order with shared macro state; `-` (or no
+//! files at all) reads standard input. Expanded text goes to standard output,
+//! and text saved by `m4wrap` is read once every file has been. Traced calls
+//! go to standard error, or to the file given with `-o`.
+
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+
+use m4rs::diagnostic::{Diagnostic, Renderer, Severity, SourceMap};
+use m4rs::error::M4Error;
+use m4rs::frozen::FrozenError;
+use m4rs::processor::{Dialect, Expander, MacroRegistry};
+use m4rs::trace::Trace;
+use m4rs::vfs::{FileProvider, IncludeFs, RealFs};
+use m4rs::warning::WarningKind;
+
+const PROGRAM: &str = "m4rs";
+
+/// Exit status for successful runs
+const EXIT_SUCCESS: u8 = 0;
+/// Exit status for errors and, with `-E`, warnings
+const EXIT_FAILURE: u8 = 1;
+/// Exit status when a frozen file was written by an incompatible version
+const EXIT_MISMATCH: u8 = 63;
+
+const HELP: &str = "\
+Usage: m4rs [OPTION]... [FILE]...
+Process macros in FILEs. If no FILE or if FILE is `-', standard input
+is read.
+
+Operation modes:
+      --help                   display this help and exit
+      --version                output version information and exit
+  -E, --fatal-warnings         once: warnings become errors, twice: stop
+                                 execution at first error
+  -Q, --quiet, --silent        suppress some warnings for builtins
+  -P, --prefix-builtins        force a `m4_' prefix to all builtins
+      --dialect=NAME           follow the rules of NAME: gnu (the default),
+                                 bsd or classic (this crate's own rules)
+
+Frozen state files:
+  -F, --freeze-state=FILE      produce a frozen state on FILE at end
+  -R, --reload-state=FILE      reload a frozen state from FILE at start
+
+Preprocessor features:
+  -D, --define=NAME[=VALUE]    define NAME as having VALUE, or empty
+  -I, --include=DIRECTORY      append DIRECTORY to include path
+  -U, --undefine=NAME          undefine NAME
+
+Limits control:
+  -G, --traditional            suppress all GNU extensions
+  -L, --nesting-limit=NUMBER   change nesting limit, 0 for unlimited [100]
+
+Debugging:
+  -o, --error-output=FILE      redirect debug and trace output to FILE
+  -t, --trace=NAME             trace NAME when it is defined
+
+Exit status is 0 for success, 1 for failure, 63 for frozen file version
+mismatch.
+";
+
+/// A `-D` or `-U` request, applied in command-line order
+enum Definition {
+    Define(String, String),
+    Undefine(String),
+}
+
+#[derive(Default)]
+struct Options {
+    definitions: Vec<Definition>,
+    include_dirs: Vec<PathBuf>,
+    files: Vec<String>,
+    prefix_builtins: bool,
+    fatal_warnings: u8,
+    quiet: bool,
+    traditional: bool,
+    dialect: Dialect,
+    nesting_limit: Option<usize>,
+    error_output: Option<String>,
+    traces: Vec<String>,
+    freeze_state: Option<String>,
+    reload_state: Option<String>,
+}
+
+/// What the command line asked for
+enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+/// Options that take a value, as (short, long) names
+const VALUE_OPTIONS: &[(char, &str)] = &[
+    ('D', "define"),
+    ('I', "include"),
+    ('U', "undefine"),
+    ('L', "nesting-limit"),
+    ('o', "error-output"),
+    ('t', "trace"),
+    ('F', "freeze-state"),
+    ('R', "reload-state"),
+];
+
+/// GNU m4's `-s` is refused rather than ignored, as output relying on sync
+/// lines would silently come out wrong
+const NO_SYNCLINES: &str = "option '--synclines' (-s) is not supported: \
+                            sync lines are not generated";
+
+/// Options without a value, as (short, long) names
+const FLAG_OPTIONS: &[(char, &str)] = &[
+    ('E', "fatal-warnings"),
+    ('Q', "quiet"),
+    ('Q', "silent"),
+    ('P', "prefix-builtins"),
+    ('G', "traditional"),
+];
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut options = Options {
+        dialect: Dialect::Gnu,
+        ..Options::default()
+    };
+    let mut args = args.into_iter();
+    let mut only_files = false;
+
+    while let Some(arg) = args.next() {
+        if only_files || arg == "-" || !arg.starts_with('-') {
+            options.files.push(arg);
+            continue;
+        }
+        if arg == "--" {
+            only_files = true;
+            continue;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (long, None),
+            };
+            match name {
+                "help" => return Ok(Command::Help),
+                "version" => return Ok(Command::Version),
+                "synclines" => return Err(NO_SYNCLINES.to_owned()),
+                "dialect" => {
+                    let Some(value) = inline.or_else(|| args.next()) else {
+                        return Err("option '--dialect' requires an argument".to_owned());
+                    };
+                    options.dialect = Dialect::from_name(&value)
+                        .ok_or_else(|| format!("invalid dialect '{}'", value))?;
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(&(short, _)) = FLAG_OPTIONS.iter().find(|(_, l)| *l == name) {
+                if inline.is_some() {
+                    return Err(format!("option '--{}' doesn't allow an argument", name));
+                }
+                apply_flag(&mut options, short);
+            } else if let Some(&(short, _)) = VALUE_OPTIONS.iter().find(|(_, l)| *l == name) {
+                let value = match inline.or_else(|| args.next()) {
+                    Some(value) => value,
+                    None => return Err(format!("option '--{}' requires an argument", name)),
+                };
+                apply_value(&mut options, short, value)?;
+            } else {
+                return Err(format!("unrecognized option '--{}'", name));
+            }
+            continue;
+        }
+
+        // Cluster of short options, e.g. `-QE` or `-DNAME=VALUE`
+        for (i, c) in arg[1..].char_indices() {
+            if c == 's' {
+                return Err(NO_SYNCLINES.to_owned());
+            } else if FLAG_OPTIONS.iter().any(|(s, _)| *s == c) {
+                apply_flag(&mut options, c);
+            } else if VALUE_OPTIONS.iter().any(|(s, _)| *s == c) {
+                let rest = &arg[1 + i + c.len_utf8()..];
+                let value = if !rest.is_empty() {
+                    rest.to_owned()
+                } else {
+                    match args.next() {
+                        Some(value) => value,
+                        None => return Err(format!("option requires an argument -- '{}'", c)),
+                    }
+                };
+                apply_value(&mut options, c, value)?;
+                break;
+            } else {
+                return Err(format!("invalid option -- '{}'", c));
+            }
+        }
+    }
+
+    Ok(Command::Run(options))
+}
+
+fn apply_flag(options: &mut Options, short: char) {
+    match short {
+        'E' => options.fatal_warnings = options.fatal_warnings.saturating_add(1),
+        // Disables every kind of warning
+        'Q' => options.quiet = true,
+        'P' => options.prefix_builtins = true,
+        'G' => options.traditional = true,
+        _ => unreachable!("unknown flag option -{}", short),
+    }
+}
+
+fn apply_value(options: &mut Options, short: char, value: String) -> Result<(), String> {
+    match short {
+        'D' => {
+            let (name, body) = match value.split_once('=') {
+                Some((name, body)) => (name.to_owned(), body.to_owned()),
+                None => (value, String::new()),
+            };
+            options.definitions.push(Definition::Define(name, body));
+        }
+        'U' => options.definitions.push(Definition::Undefine(value)),
+        'I' => options.include_dirs.push(PathBuf::from(value)),
+        'L' => {
+            let limit = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid nesting limit '{}'", value))?;
+            options.nesting_limit = Some(limit);
+        }
+        'o' => options.error_output = Some(value),
+        't' => options.traces.push(value),
+        'F' => options.freeze_state = Some(value),
+        'R' => options.reload_state = Some(value),
+        _ => unreachable!("unknown value option -{}", short),
+    }
+    Ok(())
+}
+
+/// Tracks whether any error was reported, which decides the exit status
+#[derive(Default)]
+struct Diagnostics {
+    failed: bool,
+    /// Warnings count as errors (`-E`)
+    fatal_warnings: bool,
+}
+
+impl Diagnostics {
+    fn error(&mut self, message: impl std::fmt::Display) {
+        eprintln!("{}: {}", PROGRAM, message);
+        self.failed = true;
+    }
+
+    fn warning(&mut self, message: impl std::fmt::Display) {
+        eprintln!("{}: warning: {}", PROGRAM, message);
+        if self.fatal_warnings {
+            self.failed = true;
+        }
+    }
+
+    /// Report with a source excerpt, in color when writing to a terminal
+    fn report(&mut self, diagnostic: &Diagnostic, sources: &SourceMap) {
+        let renderer = if io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none() {
+            Renderer::colored()
+        } else {
+            Renderer::plain()
+        };
+        eprint!("{}: {}", PROGRAM, renderer.render(diagnostic, sources));
+        if diagnostic.severity == Severity::Error || self.fatal_warnings {
+            self.failed = true;
+        }
+    }
+}
+
+/// Read an input file, searching the include path when it is not found
+/// relative to the working directory
+fn read_input(name: &str, files: &IncludeFs<RealFs>) -> io::Result<String> {
+    if name == "-" {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        return Ok(source);
+    }
+    files.read(Path::new(name))
+}
+
+fn run(options: Options) -> u8 {
+    let mut diagnostics = Diagnostics {
+        fatal_warnings: options.fatal_warnings > 0,
+        ..Diagnostics::default()
+    };
+
+    // The debug stream is created (and truncated) even if nothing is traced,
+    // as GNU m4 does
+    let mut trace_output: Box<dyn Write + Send> = match &options.error_output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                diagnostics.error(format_args!("cannot set debug file '{}': {}", path, e));
+                return EXIT_FAILURE;
+            }
+        },
+        None => Box::new(io::stderr()),
+    };
+
+    // Warnings are collected here and reported after each input
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&warnings);
+    // `include` and `sinclude` search the include path too
+    let include_path = options
+        .include_dirs
+        .iter()
+        .fold(IncludeFs::new(RealFs), |files, dir| files.with_dir(dir));
+    let mut expander = Expander::new(MacroRegistry::new())
+        .file_provider(include_path.clone())
+        .prefix_builtins(options.prefix_builtins)
+        .traditional(options.traditional)
+        .dialect(options.dialect)
+        .warning_sink(move |warning| sink.lock().unwrap().push(warning))
+        .trace_sink(move |trace: Trace| {
+            // A debug stream that cannot be written does not stop expansion
+            let _ = writeln!(trace_output, "m4trace: {}", trace);
+        })
+        // Text saved by `m4wrap` is read after the last file
+        .hold_wrapped(true)
+        // `-EE` stops at the first warning
+        .fatal_warnings(options.fatal_warnings > 1);
+    if options.quiet {
+        for &kind in WarningKind::ALL {
+            expander = expander.enable_warning(kind, false);
+        }
+    }
+    if let Some(limit) = options.nesting_limit {
+        expander = expander.nesting_limit(limit);
+    }
+    for name in &options.traces {
+        expander.trace(name, true);
+    }
+    if let Some(path) = &options.reload_state {
+        let reloaded = File::open(path)
+            .map_err(FrozenError::from)
+            .and_then(|file| expander.reload_state(file));
+        match reloaded {
+            Ok(unsupported) if !options.quiet => {
+                for name in unsupported {
+                    diagnostics.warning(format_args!(
+                        "{}: builtin `{}' is not supported and was left undefined",
+                        path, name
+                    ));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                diagnostics.error(format_args!("{}: {}", path, e));
+                return match e {
+                    FrozenError::Version(_) => EXIT_MISMATCH,
+                    _ => EXIT_FAILURE,
+                };
+            }
+        }
+    }
+
+    for definition in &options.definitions {
+        match definition {
+            Definition::Define(name, body) => {
+                expander.registry.define_source(name.clone(), body);
+            }
+            Definition::Undefine(name) => {
+                expander.undefine(name);
+            }
+        }
+    }
+
+    let files = if options.files.is_empty() {
+        vec!["-".to_owned()]
+    } else {
+        options.files
+    };
+
+    // Set by `m4exit`
+    let mut exit_status = None;
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+
+    // The files, then the text they saved with `m4wrap`
+    for name in files.iter().map(Some).chain([None]) {
+        // Text expanded before an error is still output, as GNU m4 does
+        let mut expanded = String::new();
+        let result = match name {
+            Some(name) => {
+                let source = match read_input(name, &include_path) {
+                    Ok(source) => source,
+                    Err(e) => {
+                        diagnostics.error(format_args!("cannot open '{}': {}", name, e));
+                        // `-EE` stops at the first error instead of moving on
+                        if options.fatal_warnings > 1 {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // Registered for excerpts in error reports, like included files
+                let file = expander.add_source(display_name(name), source.as_str());
+                expander.set_file(file);
+                expander.expand_into(&source, &mut expanded)
+            }
+            None => expander.expand_wrapped_into(&mut expanded),
+        };
+        for warning in warnings.lock().unwrap().drain(..) {
+            diagnostics.report(&Diagnostic::from_warning(&warning), expander.sources());
+        }
+
+        if let Err(e) = out.write_all(expanded.as_bytes()) {
+            diagnostics.error(format_args!("write error: {}", e));
+            break;
+        }
+
+        match result {
+            Ok(()) => {}
+            Err(M4Error::Abort { status, .. }) => {
+                exit_status = Some(status);
+                break;
+            }
+            Err(e) => {
+                diagnostics.report(&Diagnostic::from_error(&e), expander.sources());
+                break;
+            }
+        }
+    }
+
+    if let Err(e) = out.flush() {
+        diagnostics.error(format_args!("write error: {}", e));
+    }
+
+    if let Some(path) = &options.freeze_state {
+        let frozen = File::create(path).and_then(|file| {
+            let mut file = io::BufWriter::new(file);
+            expander.freeze_state(&mut file)?;
+            file.flush()
+        });
+        if let Err(e) = frozen {
+            diagnostics.error(format_args!("cannot freeze state to '{}': {}", path, e));
+        }
+    }
+
+    match exit_status {
+        // A clean exit still reports errors that happened before it
+        Some(EXIT_SUCCESS) | None if diagnostics.failed => EXIT_FAILURE,
+        Some(status) => status,
+        None => EXIT_SUCCESS,
+    }
+}
+
+fn display_name(name: &str) -> &str {
+    if name == "-" { "stdin" } else { name }
+}
+
+fn main() -> ExitCode {
+    let command = match parse_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{}: {}", PROGRAM, message);
+            eprintln!("Try '{} --help' for more information.", PROGRAM);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+
+    match command {
+        Command::Help => {
+            print!("{}", HELP);
+            ExitCode::from(EXIT_SUCCESS)
+        }
+        Command::Version => {
+            println!("{} {}", PROGRAM, env!("CARGO_PKG_VERSION"));
+            ExitCode::from(EXIT_SUCCESS)
+        }
+        Command::Run(options) => ExitCode::from(run(options)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        match parse_args(args.iter().map(|s| s.to_string())) {
+            Ok(Command::Run(options)) => options,
+            Ok(_) => panic!("expected a run command"),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_short_and_long_options() {
+        let options = parse(&[
+            "-DFOO=1",
+            "-D",
+            "BAR",
+            "--undefine=BAZ",
+            "-PE",
+            "-L50",
+            "--trace=f",
+            "-tg",
+            "--include",
+            "lib",
+            "a.m4",
+            "-",
+        ]);
+        assert!(matches!(
+            &options.definitions[..],
+            [
+                Definition::Define(foo, one),
+                Definition::Define(bar, empty),
+                Definition::Undefine(baz),
+            ] if foo == "FOO" && one == "1" && bar == "BAR" && empty.is_empty() && baz == "BAZ"
+        ));
+        assert_eq!(options.fatal_warnings, 1);
+        assert!(options.prefix_builtins);
+        assert_eq!(options.nesting_limit, Some(50));
+        assert_eq!(options.traces, vec!["f", "g"]);
+        assert_eq!(options.include_dirs, vec![PathBuf::from("lib")]);
+        assert_eq!(options.files, vec!["a.m4", "-"]);
+    }
+
+    #[test]
+    fn test_dialect_option() {
+        assert_eq!(parse(&[]).dialect, Dialect::Gnu);
+        assert_eq!(parse(&["--dialect=classic"]).dialect, Dialect::Classic);
+        assert_eq!(parse(&["--dialect=bsd"]).dialect, Dialect::Bsd);
+        assert_eq!(parse(&["--dialect", "gnu", "a.m4"]).dialect, Dialect::Gnu);
+    }
+
+    #[test]
+    fn test_double_dash_ends_options() {
+        let options = parse(&["-P", "--", "-G"]);
+        assert!(options.prefix_builtins);
+        assert!(!options.traditional);
+        assert_eq!(options.files, vec!["-G"]);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(parse_args(["-x".to_string()]).is_err());
+        assert!(parse_args(["--bogus".to_string()]).is_err());
+        assert!(parse_args(["-D".to_string()]).is_err());
+        assert!(parse_args(["--prefix-builtins=yes".to_string()]).is_err());
+        assert!(parse_args(["-Lmany".to_string()]).is_err());
+        assert!(parse_args(["--dialect=sysv".to_string()]).is_err());
+        assert!(parse_args(["--dialect".to_string()]).is_err());
+        for synclines in ["-s", "-Es", "--synclines"] {
+            let error = parse_args([synclines.to_string()]).err().unwrap();
+            assert!(error.contains("not supported"), "{}", error);
+        }
+        assert!(matches!(
+            parse_args(["--version".to_string()]),
+            Ok(Command::Version)
+        ));
+    }
+}
@@ -0,0 +1,604 @@
+//! Human-readable error reports with excerpts of the source they point into.
+//!
+//! A [`Diagnostic`] describes a problem: a message, the span it is about,
+//! further labelled spans that explain it (where a macro was defined, where a
+//! quote was opened) and an optional hint. A [`Renderer`] turns it into text,
+//! looking up file names and source lines in a [`SourceMap`]:
+//!
+//! ```text
+//! error: nesting limit of 5 exceeded
+//!  --> input.m4:3:5
+//!   |
+//! 3 | deep
+//!   |     ^
+//!   |
+//!  ::: input.m4:1:1
+//!   |
+//! 1 | define(`deep', `wrap(deep)')
+//!   | ---------------------------- `deep' defined here
+//!   = note: call chain: wrap() at 3:5 repeated 5 times -> deep at 3:5
+//!   = hint: a macro may be expanding itself without end; raise the nesting limit if the recursion is intended
+//! ```
+
+use std::fmt::Write;
+
+use crate::error::{CallChain, CallSite, M4Error, Unclosed};
+use crate::parser::Rule;
+use crate::span::{FileId, Location, Span};
+use crate::warning::Warning;
+
+/// How serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+
+    /// ANSI style of the severity and of the primary carets
+    fn style(self) -> &'static str {
+        match self {
+            Severity::Error => "1;31",
+            Severity::Warning => "1;33",
+            Severity::Note => "1;32",
+        }
+    }
+}
+
+/// A span with an explanation. The message may be empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A problem to report, with the source locations that explain it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Where the problem is
+    pub primary: Option<Label>,
+    /// Related locations, such as the definition of the macro involved
+    pub secondary: Vec<Label>,
+    /// Further explanation without a location
+    pub notes: Vec<String>,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            primary: None,
+            secondary: Vec::new(),
+            notes: Vec::new(),
+            hint: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self::new(Severity::Warning, message)
+    }
+
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.primary = Some(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.secondary.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Describe an expansion error
+    pub fn from_error(error: &M4Error) -> Self {
+        let message = match error {
+            M4Error::UnexpectedEof { construct, .. } => format!("end of file in {}", construct),
+            M4Error::Io { .. } => error.to_string(),
+            M4Error::NestingLimit { limit, .. } => {
+                format!("nesting limit of {} exceeded", limit)
+            }
+            M4Error::LimitExceeded { limit, max, .. } => {
+                format!("limit of {} {} exceeded", max, limit)
+            }
+            M4Error::Cancelled { .. } => "expansion cancelled".to_string(),
+            M4Error::TimedOut { .. } => "expansion timed out".to_string(),
+            M4Error::SelfReference { name, .. } => format!("macro '{}' expands to itself", name),
+            M4Error::UnknownBuiltin { name, .. } => format!("undefined builtin '{}'", name),
+            M4Error::InvalidArgument {
+                macro_name,
+                message,
+                ..
+            }
+            | M4Error::PolicyViolation {
+                macro_name,
+                message,
+                ..
+            } => format!("{}: {}", macro_name, message),
+            M4Error::Abort { status, .. } => format!("exit requested with status {}", status),
+            M4Error::FatalWarning { warning } => warning.message.clone(),
+        };
+        let mut diagnostic = Diagnostic::error(message);
+
+        // Point at what was left open rather than at the end of the input
+        let primary = match error {
+            M4Error::UnexpectedEof {
+                construct, opened, ..
+            } => {
+                let what = match construct {
+                    Unclosed::String => "quote",
+                    Unclosed::ArgumentList => "argument list",
+                    Unclosed::Comment => "comment",
+                };
+                Some((*opened, format!("{} opened here", what)))
+            }
+            _ => error.location().map(|location| (location, String::new())),
+        };
+        let primary = primary.zip(error.file());
+        if let Some(((location, label), file)) = &primary {
+            diagnostic = diagnostic.with_primary(at(*file, *location), label);
+        }
+        diagnostic = diagnostic.with_call_stack(
+            primary.map(|((location, _), file)| (file, location)),
+            error.call_stack(),
+        );
+
+        match error {
+            M4Error::NestingLimit { stack, .. } => {
+                diagnostic = diagnostic.with_note(format!("call chain: {}", CallChain(stack)));
+            }
+            M4Error::Cancelled { progress, .. } | M4Error::TimedOut { progress, .. } => {
+                diagnostic = diagnostic.with_note(format!("stopped after {}", progress));
+            }
+            _ => {}
+        }
+
+        let hint = match error {
+            M4Error::UnexpectedEof { construct, .. } => Some(match construct {
+                Unclosed::String => "every ` opens a quote that needs a matching '",
+                Unclosed::ArgumentList => "the arguments of a macro call end at the matching `)'",
+                Unclosed::Comment => "a comment runs up to and including the next newline",
+            }),
+            M4Error::NestingLimit { .. } => Some(
+                "a macro may be expanding itself without end; \
+                 raise the nesting limit if the recursion is intended",
+            ),
+            M4Error::LimitExceeded { .. } => Some(
+                "a few nested definitions can expand to exponentially much text; \
+                 raise the limit if the input is trusted",
+            ),
+            M4Error::SelfReference { .. } => Some(
+                "quote the name in the definition to output it as text, \
+                 as in define(`a', ``a'')",
+            ),
+            M4Error::UnknownBuiltin { .. } => Some("`builtin' can only call builtin macros"),
+            M4Error::PolicyViolation { .. } => {
+                Some("the expander's sandbox policy does not allow this")
+            }
+            _ => None,
+        };
+        match hint {
+            Some(hint) => diagnostic.with_hint(hint),
+            None => diagnostic,
+        }
+    }
+
+    /// Describe a warning
+    pub fn from_warning(warning: &Warning) -> Self {
+        Diagnostic::warning(warning.message.clone())
+            .with_primary(at(warning.file, warning.location), "")
+            .with_call_stack(Some((warning.file, warning.location)), &warning.stack)
+    }
+
+    /// Label the calls of `stack` that explain a problem at `primary`.
+    /// The outermost call shows where in the input the expansion started;
+    /// the innermost one is the macro at fault.
+    fn with_call_stack(mut self, primary: Option<(FileId, Location)>, stack: &[CallSite]) -> Self {
+        if let Some(site) = stack.first()
+            && primary != Some((site.file, site.location))
+        {
+            self = self.with_secondary(
+                at(site.file, site.location),
+                format!("in expansion of `{}'", site.name),
+            );
+        }
+        if let Some(site) = stack.last()
+            && let Some(definition) = site.definition
+        {
+            self = self.with_secondary(definition, format!("`{}' defined here", site.name));
+        }
+        self
+    }
+
+    /// Describe a failure of [`M4Parser`](crate::parser::M4Parser) to parse
+    /// source registered as `file`
+    pub fn from_parse_error(error: &pest::error::Error<Rule>, file: FileId) -> Self {
+        use pest::error::{InputLocation, LineColLocation};
+
+        let (start, end) = match error.line_col {
+            LineColLocation::Pos(start) => (start, start),
+            LineColLocation::Span(start, end) => (start, end),
+        };
+        let (start_offset, end_offset) = match error.location {
+            InputLocation::Pos(offset) => (offset, offset),
+            InputLocation::Span(span) => span,
+        };
+        let span = Span {
+            file,
+            start: Location {
+                offset: start_offset,
+                line: start.0,
+                column: start.1,
+            },
+            end: Location {
+                offset: end_offset,
+                line: end.0,
+                column: end.1,
+            },
+        };
+        Diagnostic::error(error.variant.message()).with_primary(span, "")
+    }
+}
+
+/// Empty span at `location`; rendered as a single caret
+fn at(file: FileId, location: Location) -> Span {
+    Span {
+        file,
+        start: location,
+        end: location,
+    }
+}
+
+/// Names and text of the sources diagnostics point into
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<(String, String)>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a source. Ids are handed out in order, starting at
+    /// `FileId(0)`.
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> FileId {
+        self.files.push((name.into(), text.into()));
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    /// Id of a source registered earlier with the same name and text
+    pub fn find(&self, name: &str, text: &str) -> Option<FileId> {
+        self.files
+            .iter()
+            .position(|(n, t)| n == name && t == text)
+            .map(|i| FileId(i as u32))
+    }
+
+    pub fn name(&self, file: FileId) -> Option<&str> {
+        self.files
+            .get(file.0 as usize)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn text(&self, file: FileId) -> Option<&str> {
+        self.files
+            .get(file.0 as usize)
+            .map(|(_, text)| text.as_str())
+    }
+}
+
+/// Turns diagnostics into text, either plain or with ANSI colors
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Renderer {
+    color: bool,
+}
+
+const GUTTER_STYLE: &str = "1;34";
+const SECONDARY_STYLE: &str = "1;34";
+const HINT_STYLE: &str = "1;36";
+
+impl Renderer {
+    pub fn plain() -> Self {
+        Self { color: false }
+    }
+
+    pub fn colored() -> Self {
+        Self { color: true }
+    }
+
+    /// Render `diagnostic`, ending with a newline
+    pub fn render(&self, diagnostic: &Diagnostic, sources: &SourceMap) -> String {
+        let mut out = String::new();
+        self.paint(
+            &mut out,
+            diagnostic.severity.style(),
+            diagnostic.severity.name(),
+        );
+        out.push_str(": ");
+        self.paint(&mut out, "1", &diagnostic.message);
+        out.push('\n');
+
+        let labels = diagnostic.primary.iter().chain(&diagnostic.secondary);
+        let width = labels
+            .map(|label| label.span.start.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+
+        if let Some(primary) = &diagnostic.primary {
+            self.snippet(&mut out, sources, primary, Some(diagnostic.severity), width);
+        }
+        for label in &diagnostic.secondary {
+            self.gutter(&mut out, width, None);
+            out.push('\n');
+            self.snippet(&mut out, sources, label, None, width);
+        }
+        for note in &diagnostic.notes {
+            out.push_str(&" ".repeat(width + 1));
+            self.paint(&mut out, GUTTER_STYLE, "=");
+            out.push(' ');
+            self.paint(&mut out, "1", "note");
+            let _ = writeln!(out, ": {}", note);
+        }
+        if let Some(hint) = &diagnostic.hint {
+            out.push_str(&" ".repeat(width + 1));
+            self.paint(&mut out, GUTTER_STYLE, "=");
+            out.push(' ');
+            self.paint(&mut out, HINT_STYLE, "hint");
+            let _ = writeln!(out, ": {}", hint);
+        }
+        out
+    }
+
+    /// Header with the position of `label`, then the first source line it
+    /// covers with the range underlined. The primary label is marked in the
+    /// color of its `severity`; secondary ones have none.
+    fn snippet(
+        &self,
+        out: &mut String,
+        sources: &SourceMap,
+        label: &Label,
+        severity: Option<Severity>,
+        width: usize,
+    ) {
+        let (arrow, mark, style) = match severity {
+            Some(severity) => ("-->", '^', severity.style()),
+            None => (":::", '-', SECONDARY_STYLE),
+        };
+        let Span { file, start, end } = label.span;
+        out.push_str(&" ".repeat(width));
+        self.paint(out, GUTTER_STYLE, arrow);
+        let _ = writeln!(
+            out,
+            " {}:{}",
+            sources.name(file).unwrap_or("<input>"),
+            start
+        );
+
+        let Some(text) = sources.text(file) else {
+            return;
+        };
+        let offset = floor_char_boundary(text, start.offset);
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+        let line = &text[line_start..line_end];
+
+        // Underline up to the end of the span, or of the line if the span
+        // continues past it; always at least one character
+        let covered = if end.offset > offset {
+            &text[offset..floor_char_boundary(text, end.offset.min(line_end))]
+        } else {
+            ""
+        };
+        let length = covered.chars().count().max(1);
+        // Keep tabs so the marks line up with the source however it is shown
+        let padding: String = text[line_start..offset]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        self.gutter(out, width, None);
+        out.push('\n');
+        self.gutter(out, width, Some(start.line));
+        out.push(' ');
+        out.push_str(line);
+        out.push('\n');
+        self.gutter(out, width, None);
+        out.push(' ');
+        out.push_str(&padding);
+        self.paint(out, style, &mark.to_string().repeat(length));
+        if !label.message.is_empty() {
+            out.push(' ');
+            self.paint(out, style, &label.message);
+        }
+        out.push('\n');
+    }
+
+    /// Start a line with the line-number column, numbered or blank
+    fn gutter(&self, out: &mut String, width: usize, number: Option<usize>) {
+        let number = number.map_or(String::new(), |n| n.to_string());
+        self.paint(out, GUTTER_STYLE, &format!("{:>width$} |", number));
+    }
+
+    fn paint(&self, out: &mut String, style: &str, text: &str) {
+        if self.color {
+            let _ = write!(out, "\x1b[{}m{}\x1b[0m", style, text);
+        } else {
+            out.push_str(text);
+        }
+    }
+}
+
+/// Largest char boundary of `text` at or before `offset`
+fn floor_char_boundary(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{Expander, MacroRegistry};
+    use crate::vfs::MemoryFs;
+
+    fn render_error(name: &str, source: &str, limit: usize) -> String {
+        let mut expander = Expander::new(MacroRegistry::new())
+            .nesting_limit(limit)
+            .file_provider(MemoryFs::new().with_file("lib.m4", "one\n  deep\n"));
+        let file = expander.add_source(name, source);
+        expander.set_file(file);
+        let error = expander.expand(source).unwrap_err();
+        Renderer::plain().render(&Diagnostic::from_error(&error), expander.sources())
+    }
+
+    #[test]
+    fn test_render_unterminated_quote() {
+        assert_eq!(
+            render_error("in.m4", "one\ntwo `three\n", 100),
+            "\
+error: end of file in string
+ --> in.m4:2:5
+  |
+2 | two `three
+  |     ^ quote opened here
+  = hint: every ` opens a quote that needs a matching '
+"
+        );
+    }
+
+    #[test]
+    fn test_render_secondary_labels() {
+        let source = "define(`wrap', `[$1]')\ndefine(`deep', `wrap(deep)')\n\tdeep\n";
+        // Tabs are kept in front of the caret so it lines up
+        assert_eq!(
+            render_error("input.m4", source, 5),
+            "\
+error: nesting limit of 5 exceeded
+ --> input.m4:3:6
+  |
+3 | \tdeep
+  | \t    ^
+  |
+ ::: input.m4:2:1
+  |
+2 | define(`deep', `wrap(deep)')
+  | ---------------------------- `deep' defined here
+  = note: call chain: wrap() at 3:6 repeated 5 times -> deep at 3:6
+  = hint: a macro may be expanding itself without end; \
+raise the nesting limit if the recursion is intended
+"
+        );
+    }
+
+    #[test]
+    fn test_render_included() {
+        let source = "define(`wrap', `[$1]')define(`deep', `wrap(deep)')\ninclude(`lib.m4')";
+        assert_eq!(
+            render_error("main.m4", source, 5),
+            "\
+error: nesting limit of 5 exceeded
+ --> lib.m4:2:7
+  |
+2 |   deep
+  |       ^
+  |
+ ::: main.m4:1:23
+  |
+1 | define(`wrap', `[$1]')define(`deep', `wrap(deep)')
+  |                       ---------------------------- `deep' defined here
+  = note: call chain: wrap() at 2:7 repeated 5 times -> deep at 2:7
+  = hint: a macro may be expanding itself without end; \
+raise the nesting limit if the recursion is intended
+"
+        );
+    }
+
+    #[test]
+    fn test_render_colored() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("x", "abc");
+        let span = Span {
+            file,
+            start: Location::START,
+            end: Location::START.advance("ab"),
+        };
+        let diagnostic = Diagnostic::warning("careful").with_primary(span, "here");
+        let plain = Renderer::plain().render(&diagnostic, &sources);
+        assert_eq!(
+            plain,
+            "warning: careful\n --> x:1:1\n  |\n1 | abc\n  | ^^ here\n"
+        );
+        let colored = Renderer::colored().render(&diagnostic, &sources);
+        assert!(colored.starts_with("\x1b[1;33mwarning\x1b[0m: "));
+        // Stripping the escapes leaves the plain rendering
+        let mut stripped = String::new();
+        let mut rest = colored.as_str();
+        while let Some(i) = rest.find('\x1b') {
+            stripped.push_str(&rest[..i]);
+            rest = &rest[i + rest[i..].find('m').unwrap() + 1..];
+        }
+        stripped.push_str(rest);
+        assert_eq!(stripped, plain);
+    }
+
+    #[test]
+    fn test_render_unknown_source() {
+        let error = M4Error::UnknownBuiltin {
+            name: "oops".into(),
+            file: FileId(7),
+            location: Location::START,
+            stack: Vec::new(),
+        };
+        let diagnostic = Diagnostic::from_error(&error);
+        assert_eq!(
+            Renderer::plain().render(&diagnostic.with_hint("none"), &SourceMap::new()),
+            "error: undefined builtin 'oops'\n --> <input>:1:1\n  = hint: none\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("in.m4", "x\nf(a, `b");
+        let error = crate::parser::M4Parser::parse_input("x\nf(a, `b").unwrap_err();
+        let diagnostic = Diagnostic::from_parse_error(&error, file);
+        assert_eq!(
+            Renderer::plain().render(&diagnostic, &sources),
+            "error: end of file in string\n --> in.m4:2:6\n  |\n2 | f(a, `b\n  |      ^^\n"
+        );
+    }
+}
@@ -0,0 +1,355 @@
+//! Errors reported while loading and expanding m4 input.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::span::{FileId, Location, Span};
+use crate::warning::Warning;
+
+/// A macro call that was in progress when an error occurred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+    pub name: String,
+    /// Input the call was read from
+    pub file: FileId,
+    /// Where the call's name was read
+    pub location: Location,
+    /// Where the macro was defined, if known
+    pub definition: Option<Span>,
+    /// Arguments read so far, each shortened to
+    /// [`CallSite::ARGUMENT_PREVIEW`] characters
+    pub arguments: Vec<String>,
+}
+
+impl CallSite {
+    /// Characters of each argument kept in [`CallSite::arguments`]
+    pub const ARGUMENT_PREVIEW: usize = 20;
+
+    /// Shorten `args` for [`CallSite::arguments`]
+    pub fn preview(args: &[String]) -> Vec<String> {
+        args.iter()
+            .map(|arg| match arg.char_indices().nth(Self::ARGUMENT_PREVIEW) {
+                Some((end, _)) => format!("{}...", &arg[..end]),
+                None => arg.clone(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for CallSite {
+    /// The call as `name(args)` followed by where it was read
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if !self.arguments.is_empty() {
+            write!(f, "({})", self.arguments.join(", "))?;
+        }
+        write!(f, " at {}", self.location)
+    }
+}
+
+/// Longest sequence of calls [`CallChain`] looks for repetitions of
+const MAX_CYCLE: usize = 8;
+
+/// Displays a call stack, outermost call first, as `a at 1:1 -> b at 2:1`.
+/// Calls that repeat one after the other, singly or in cycles of a few
+/// calls, are shown once with the number of repetitions:
+/// `[a at 1:1 -> b at 1:5] repeated 48 times`. Calls count as the same
+/// when they have the same name.
+pub struct CallChain<'a>(pub &'a [CallSite]);
+
+impl fmt::Display for CallChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let calls = self.0;
+        let same = |a: usize, b: usize| calls[a].name == calls[b].name;
+        let mut i = 0;
+        while i < calls.len() {
+            if i > 0 {
+                f.write_str(" -> ")?;
+            }
+            // The cycle starting here that covers the most calls
+            let (mut period, mut count) = (1, 1);
+            for p in 1..=MAX_CYCLE.min((calls.len() - i) / 2) {
+                let mut k = 1;
+                while i + (k + 1) * p <= calls.len() && (0..p).all(|j| same(i + j, i + k * p + j)) {
+                    k += 1;
+                }
+                if k > 1 && p * k > period * count {
+                    (period, count) = (p, k);
+                }
+            }
+
+            let cycle = &calls[i..i + period];
+            if period > 1 {
+                f.write_str("[")?;
+            }
+            for (j, call) in cycle.iter().enumerate() {
+                if j > 0 {
+                    f.write_str(" -> ")?;
+                }
+                write!(f, "{}", call)?;
+            }
+            if period > 1 {
+                f.write_str("]")?;
+            }
+            if count > 1 {
+                write!(f, " repeated {} times", count)?;
+            }
+            i += period * count;
+        }
+        Ok(())
+    }
+}
+
+/// Construct still open when the input ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unclosed {
+    /// Quoted string
+    String,
+    /// Arguments of a macro call
+    ArgumentList,
+    Comment,
+}
+
+impl fmt::Display for Unclosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Unclosed::String => "string",
+            Unclosed::ArgumentList => "argument list",
+            Unclosed::Comment => "comment",
+        })
+    }
+}
+
+/// Resource whose use is bounded by
+/// [`ExpanderLimits`](crate::processor::ExpanderLimits), apart from nesting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Macro calls started during one expansion
+    Invocations,
+    /// Bytes of expanded output
+    OutputBytes,
+    /// Bytes collected into a single macro argument
+    ArgumentBytes,
+    /// Macros defined at once
+    Definitions,
+}
+
+impl fmt::Display for Limit {
+    /// What the limit counts, as in `limit of 10 macro calls`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Invocations => "macro calls",
+            Limit::OutputBytes => "bytes of output",
+            Limit::ArgumentBytes => "bytes in an argument",
+            Limit::Definitions => "definitions",
+        })
+    }
+}
+
+/// How far expansion got before it was stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Progress {
+    /// Macro calls started
+    pub invocations: usize,
+    /// Bytes of expanded output produced
+    pub output_bytes: usize,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} macro calls and {} bytes of output",
+            self.invocations, self.output_bytes
+        )
+    }
+}
+
+/// Failure to load or expand m4 input
+#[derive(Debug, Error)]
+pub enum M4Error {
+    /// The input ended inside a quoted string, a comment or the arguments
+    /// of a macro call
+    #[error("{location}: end of file in {construct} opened at {opened}")]
+    UnexpectedEof {
+        construct: Unclosed,
+        /// Where the construct was opened: the opening quote or comment
+        /// character, or the name of the macro being called
+        opened: Location,
+        /// Input `opened` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Macro calls nested deeper than the limit. The message shows the
+    /// calls, with repeated cycles collapsed.
+    #[error(
+        "{location}: nesting limit of {limit} exceeded; call chain: {}",
+        CallChain(stack)
+    )]
+    NestingLimit {
+        limit: usize,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    /// Expansion used more of a resource than its limits allow
+    #[error("{location}: limit of {max} {limit} exceeded")]
+    LimitExceeded {
+        limit: Limit,
+        max: usize,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    /// Expansion was stopped through its cancellation token
+    #[error("{location}: expansion cancelled after {progress}")]
+    Cancelled {
+        progress: Progress,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    /// Expansion ran past its deadline
+    #[error("{location}: expansion timed out after {progress}")]
+    TimedOut {
+        progress: Progress,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    /// A macro expands to a call of itself without arguments, which would
+    /// never finish
+    #[error("{location}: macro '{name}' expands to itself")]
+    SelfReference {
+        name: String,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    #[error("{location}: undefined builtin '{name}'")]
+    UnknownBuiltin {
+        name: String,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    #[error("{location}: {macro_name}: {message}")]
+    InvalidArgument {
+        macro_name: String,
+        message: String,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    /// A builtin call was refused by the
+    /// [`SandboxPolicy`](crate::sandbox::SandboxPolicy)
+    #[error("{location}: {macro_name}: {message}")]
+    PolicyViolation {
+        macro_name: String,
+        message: String,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+    /// A warning was issued while warnings are fatal
+    #[error("{warning}")]
+    FatalWarning { warning: Warning },
+    /// Expansion was stopped on request of the input, by `m4exit`
+    #[error("{location}: exit requested with status {status}")]
+    Abort {
+        status: u8,
+        /// Input `location` is in
+        file: FileId,
+        location: Location,
+        stack: Vec<CallSite>,
+    },
+}
+
+impl M4Error {
+    /// Where in the input the error was detected, if it came from expansion
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            M4Error::UnexpectedEof { location, .. }
+            | M4Error::NestingLimit { location, .. }
+            | M4Error::LimitExceeded { location, .. }
+            | M4Error::Cancelled { location, .. }
+            | M4Error::TimedOut { location, .. }
+            | M4Error::SelfReference { location, .. }
+            | M4Error::UnknownBuiltin { location, .. }
+            | M4Error::InvalidArgument { location, .. }
+            | M4Error::PolicyViolation { location, .. }
+            | M4Error::Abort { location, .. } => Some(*location),
+            M4Error::FatalWarning { warning } => Some(warning.location),
+            M4Error::Io { .. } => None,
+        }
+    }
+
+    /// Input [`M4Error::location`] is in, or for
+    /// [`M4Error::UnexpectedEof`], the input the unclosed construct was
+    /// opened in
+    pub fn file(&self) -> Option<FileId> {
+        match self {
+            M4Error::UnexpectedEof { file, .. }
+            | M4Error::NestingLimit { file, .. }
+            | M4Error::LimitExceeded { file, .. }
+            | M4Error::Cancelled { file, .. }
+            | M4Error::TimedOut { file, .. }
+            | M4Error::SelfReference { file, .. }
+            | M4Error::UnknownBuiltin { file, .. }
+            | M4Error::InvalidArgument { file, .. }
+            | M4Error::PolicyViolation { file, .. }
+            | M4Error::Abort { file, .. } => Some(*file),
+            M4Error::FatalWarning { warning } => Some(warning.file),
+            M4Error::Io { .. } => None,
+        }
+    }
+
+    /// Macro calls in progress when the error occurred, outermost first
+    pub fn call_stack(&self) -> &[CallSite] {
+        match self {
+            M4Error::UnexpectedEof { stack, .. }
+            | M4Error::NestingLimit { stack, .. }
+            | M4Error::LimitExceeded { stack, .. }
+            | M4Error::Cancelled { stack, .. }
+            | M4Error::TimedOut { stack, .. }
+            | M4Error::SelfReference { stack, .. }
+            | M4Error::UnknownBuiltin { stack, .. }
+            | M4Error::InvalidArgument { stack, .. }
+            | M4Error::PolicyViolation { stack, .. }
+            | M4Error::Abort { stack, .. } => stack,
+            M4Error::FatalWarning { warning } => &warning.stack,
+            M4Error::Io { .. } => &[],
+        }
+    }
+}
+
+/// Wraps the error so it can be passed through `io::Read` and recovered with
+/// [`io::Error::get_ref`] and `downcast_ref::<M4Error>()`
+impl From<M4Error> for io::Error {
+    fn from(error: M4Error) -> Self {
+        let kind = match &error {
+            M4Error::Io { source, .. } => source.kind(),
+            M4Error::TimedOut { .. } => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error)
+    }
+}
@@ -0,0 +1,415 @@
+//! Integer arithmetic for the `eval` builtin, following GNU m4.
+//!
+//! Expressions work on 32-bit signed integers that wrap around on overflow,
+//! with C's operators and precedence and `**` for exponentiation, which
+//! binds less tightly than the unary operators. Numbers are decimal, octal
+//! with a leading `0`, hexadecimal with `0x`, binary with `0b`, or in any
+//! radix up to 36 as `0r<radix>:<digits>`. The right side of `&&` and `||`
+//! is only checked for errors if it is evaluated.
+
+use std::fmt;
+
+/// Why an expression could not be evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalError {
+    DivideByZero,
+    ModuloByZero,
+    NegativeExponent,
+    /// A character that starts no number or operator, or a digit the
+    /// number's radix does not have
+    BadInput,
+    /// An operand is missing
+    BadExpression,
+    MissingParenthesis,
+    /// Text follows a complete expression
+    ExcessInput,
+    /// An assignment, which m4 does not have
+    InvalidOperator,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EvalError::DivideByZero => "divide by zero",
+            EvalError::ModuloByZero => "modulo by zero",
+            EvalError::NegativeExponent => "negative exponent",
+            EvalError::BadInput => "bad input",
+            EvalError::BadExpression => "bad expression",
+            EvalError::MissingParenthesis => "missing right parenthesis",
+            EvalError::ExcessInput => "excess input",
+            EvalError::InvalidOperator => "invalid operator",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Number(i32),
+    Op(Op),
+    Open,
+    Close,
+    End,
+}
+
+/// Binary operators from the loosest binding to the tightest, above `**`
+const LEVELS: &[&[Op]] = &[
+    &[Op::Or],
+    &[Op::And],
+    &[Op::BitOr],
+    &[Op::BitXor],
+    &[Op::BitAnd],
+    &[Op::Eq, Op::Ne],
+    &[Op::Lt, Op::Le, Op::Gt, Op::Ge],
+    &[Op::Shl, Op::Shr],
+    &[Op::Add, Op::Sub],
+    &[Op::Mul, Op::Div, Op::Mod],
+];
+
+/// Evaluate `expression`
+pub fn evaluate(expression: &str) -> Result<i32, EvalError> {
+    let mut parser = Parser {
+        text: expression,
+        pos: 0,
+        peeked: None,
+    };
+    let value = parser.binary(0, true)?;
+    match parser.next()? {
+        Token::End => Ok(value),
+        Token::Close => Err(EvalError::BadExpression),
+        _ => Err(EvalError::ExcessInput),
+    }
+}
+
+/// Write `value` in `radix`, from 1 (a `1` for each unit) to 36, with at
+/// least `width` digits
+pub fn format(value: i32, radix: u32, width: usize) -> String {
+    let mut magnitude = value.unsigned_abs();
+    let mut digits = Vec::new();
+    if radix == 1 {
+        digits.resize(magnitude as usize, b'1');
+    } else {
+        // Zero has a digit too
+        loop {
+            let digit = char::from_digit(magnitude % radix, radix).expect("digit is below radix");
+            digits.push(digit as u8);
+            magnitude /= radix;
+            if magnitude == 0 {
+                break;
+            }
+        }
+        digits.reverse();
+    }
+    let mut text = String::with_capacity(width.max(digits.len()) + 1);
+    if value < 0 {
+        text.push('-');
+    }
+    text.extend(std::iter::repeat_n('0', width.saturating_sub(digits.len())));
+    text.push_str(std::str::from_utf8(&digits).expect("digits are ASCII"));
+    text
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    peeked: Option<Token>,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Result<Token, EvalError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lex()?);
+        }
+        Ok(self.peeked.expect("token was just read"))
+    }
+
+    fn next(&mut self) -> Result<Token, EvalError> {
+        let token = self.peek()?;
+        self.peeked = None;
+        Ok(token)
+    }
+
+    /// Operators of `level` and tighter. Errors that only evaluating can
+    /// find, like division by zero, are reported if `evaluate` is set.
+    fn binary(&mut self, level: usize, evaluate: bool) -> Result<i32, EvalError> {
+        let Some(ops) = LEVELS.get(level) else {
+            return self.power(evaluate);
+        };
+        let mut value = self.binary(level + 1, evaluate)?;
+        while let Token::Op(op) = self.peek()?
+            && ops.contains(&op)
+        {
+            self.next()?;
+            // The right side of `&&` and `||` is skipped if the left decides
+            let evaluate_right = match op {
+                Op::And => evaluate && value != 0,
+                Op::Or => evaluate && value == 0,
+                _ => evaluate,
+            };
+            let right = self.binary(level + 1, evaluate_right)?;
+            value = if evaluate_right {
+                apply(op, value, right)?
+            } else {
+                (op == Op::Or && value != 0) as i32
+            };
+        }
+        Ok(value)
+    }
+
+    /// `**`, which groups to the right
+    fn power(&mut self, evaluate: bool) -> Result<i32, EvalError> {
+        let base = self.unary(evaluate)?;
+        if self.peek()? != Token::Op(Op::Pow) {
+            return Ok(base);
+        }
+        self.next()?;
+        let exponent = self.power(evaluate)?;
+        if !evaluate {
+            Ok(0)
+        } else if exponent < 0 {
+            Err(EvalError::NegativeExponent)
+        } else {
+            Ok(base.wrapping_pow(exponent as u32))
+        }
+    }
+
+    fn unary(&mut self, evaluate: bool) -> Result<i32, EvalError> {
+        match self.next()? {
+            Token::Number(n) => Ok(n),
+            Token::Open => {
+                let value = self.binary(0, evaluate)?;
+                match self.next()? {
+                    Token::Close => Ok(value),
+                    Token::End => Err(EvalError::MissingParenthesis),
+                    _ => Err(EvalError::ExcessInput),
+                }
+            }
+            Token::Op(Op::Add) => self.unary(evaluate),
+            Token::Op(Op::Sub) => Ok(self.unary(evaluate)?.wrapping_neg()),
+            Token::Op(Op::BitNot) => Ok(!self.unary(evaluate)?),
+            Token::Op(Op::Not) => Ok((self.unary(evaluate)? == 0) as i32),
+            Token::Op(_) | Token::Close | Token::End => Err(EvalError::BadExpression),
+        }
+    }
+
+    fn lex(&mut self) -> Result<Token, EvalError> {
+        let rest = self.text[self.pos..].trim_start();
+        self.pos = self.text.len() - rest.len();
+        let Some(c) = rest.chars().next() else {
+            return Ok(Token::End);
+        };
+        if c.is_ascii_digit() {
+            return self.number();
+        }
+
+        // Longest operators first
+        const OPERATORS: &[(&str, Option<Op>)] = &[
+            ("**=", None),
+            ("<<=", None),
+            (">>=", None),
+            ("||", Some(Op::Or)),
+            ("&&", Some(Op::And)),
+            ("==", Some(Op::Eq)),
+            ("!=", Some(Op::Ne)),
+            ("<=", Some(Op::Le)),
+            (">=", Some(Op::Ge)),
+            ("<<", Some(Op::Shl)),
+            (">>", Some(Op::Shr)),
+            ("**", Some(Op::Pow)),
+            ("+=", None),
+            ("-=", None),
+            ("*=", None),
+            ("/=", None),
+            ("%=", None),
+            ("&=", None),
+            ("^=", None),
+            ("|=", None),
+            ("=", None),
+            ("|", Some(Op::BitOr)),
+            ("^", Some(Op::BitXor)),
+            ("&", Some(Op::BitAnd)),
+            ("<", Some(Op::Lt)),
+            (">", Some(Op::Gt)),
+            ("+", Some(Op::Add)),
+            ("-", Some(Op::Sub)),
+            ("*", Some(Op::Mul)),
+            ("/", Some(Op::Div)),
+            ("%", Some(Op::Mod)),
+            ("!", Some(Op::Not)),
+            ("~", Some(Op::BitNot)),
+        ];
+        let token = match c {
+            '(' => Token::Open,
+            ')' => Token::Close,
+            _ => {
+                let (text, op) = OPERATORS
+                    .iter()
+                    .find(|(text, _)| rest.starts_with(text))
+                    .ok_or(EvalError::BadInput)?;
+                self.pos += text.len();
+                return op.map(Token::Op).ok_or(EvalError::InvalidOperator);
+            }
+        };
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn number(&mut self) -> Result<Token, EvalError> {
+        let rest = &self.text[self.pos..];
+        let end = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != ':')
+            .unwrap_or(rest.len());
+        self.pos += end;
+        let literal = &rest[..end];
+
+        let lower = literal.to_ascii_lowercase();
+        let (radix, digits) = if let Some(digits) = lower.strip_prefix("0x") {
+            (16, digits)
+        } else if let Some(digits) = lower.strip_prefix("0b") {
+            (2, digits)
+        } else if let Some(spec) = lower.strip_prefix("0r") {
+            let (radix, digits) = spec.split_once(':').ok_or(EvalError::BadInput)?;
+            let radix = radix.parse().map_err(|_| EvalError::BadInput)?;
+            if !(1..=36).contains(&radix) {
+                return Err(EvalError::BadInput);
+            }
+            (radix, digits)
+        } else if lower.len() > 1
+            && let Some(digits) = lower.strip_prefix('0')
+        {
+            (8, digits)
+        } else {
+            (10, lower.as_str())
+        };
+        if digits.is_empty() {
+            return Err(EvalError::BadInput);
+        }
+
+        let mut value = 0i32;
+        for c in digits.chars() {
+            value = match (radix, c) {
+                (1, '1') => value.wrapping_add(1),
+                (1, _) => return Err(EvalError::BadInput),
+                _ => {
+                    let digit = c.to_digit(radix).ok_or(EvalError::BadInput)?;
+                    value.wrapping_mul(radix as i32).wrapping_add(digit as i32)
+                }
+            };
+        }
+        Ok(Token::Number(value))
+    }
+}
+
+fn apply(op: Op, left: i32, right: i32) -> Result<i32, EvalError> {
+    Ok(match op {
+        Op::Or => (left != 0 || right != 0) as i32,
+        Op::And => (left != 0 && right != 0) as i32,
+        Op::BitOr => left | right,
+        Op::BitXor => left ^ right,
+        Op::BitAnd => left & right,
+        Op::Eq => (left == right) as i32,
+        Op::Ne => (left != right) as i32,
+        Op::Lt => (left < right) as i32,
+        Op::Le => (left <= right) as i32,
+        Op::Gt => (left > right) as i32,
+        Op::Ge => (left >= right) as i32,
+        Op::Shl => left.wrapping_shl(right as u32),
+        Op::Shr => left.wrapping_shr(right as u32),
+        Op::Add => left.wrapping_add(right),
+        Op::Sub => left.wrapping_sub(right),
+        Op::Mul => left.wrapping_mul(right),
+        Op::Div if right == 0 => return Err(EvalError::DivideByZero),
+        Op::Div => left.wrapping_div(right),
+        Op::Mod if right == 0 => return Err(EvalError::ModuloByZero),
+        Op::Mod => left.wrapping_rem(right),
+        Op::Pow | Op::Not | Op::BitNot => unreachable!("{:?} is not a binary operator", op),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let cases = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("-3 * 5", -15),
+            ("7 / 2", 3),
+            ("-7 % 3", -1),
+            ("2 ** 3 ** 2", 512),
+            ("-2 ** 2", 4),
+            ("1 < 2 && 2 <= 2 && 3 > 2 && 3 >= 3", 1),
+            ("1 == 2 || 1 != 1", 0),
+            ("!0 + !5 + ~0", 0),
+            ("1 << 4 | 1 >> 1 ^ 3 & 6", 18),
+            ("0x1F + 0b101 + 017 + 0r3:12 + 0r1:111", 31 + 5 + 15 + 5 + 3),
+            ("2147483647 + 1", i32::MIN),
+            // Errors on the side that is not evaluated do not count
+            ("0 && 1 / 0", 0),
+            ("1 || 2 % 0", 1),
+            ("0 && 2 ** -1", 0),
+        ];
+        for (expression, value) in cases {
+            assert_eq!(evaluate(expression), Ok(value), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("1 / 0", EvalError::DivideByZero),
+            ("1 % 0", EvalError::ModuloByZero),
+            ("2 ** -1", EvalError::NegativeExponent),
+            ("1 + a", EvalError::BadInput),
+            ("09", EvalError::BadInput),
+            ("1 +", EvalError::BadExpression),
+            ("(1 + 2", EvalError::MissingParenthesis),
+            ("1 2", EvalError::ExcessInput),
+            ("1)", EvalError::BadExpression),
+            ("1 = 1", EvalError::InvalidOperator),
+            ("1 += 1", EvalError::InvalidOperator),
+        ];
+        for (expression, error) in cases {
+            assert_eq!(evaluate(expression), Err(error), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(666, 10, 1), "666");
+        assert_eq!(format(666, 11, 1), "556");
+        assert_eq!(format(666, 6, 10), "0000003030");
+        assert_eq!(format(-666, 6, 10), "-0000003030");
+        assert_eq!(format(255, 16, 1), "ff");
+        assert_eq!(format(3, 1, 5), "00111");
+        assert_eq!(format(0, 10, 0), "0");
+        assert_eq!(format(0, 1, 0), "");
+        assert_eq!(format(i32::MIN, 2, 1), format!("-1{}", "0".repeat(31)));
+    }
+}
@@ -0,0 +1,406 @@
+//! Frozen state files: a snapshot of an [`Expander`]'s macro state that can be
+//! reloaded without re-expanding the sources that produced it.
+//!
+//! The format is the text format written by GNU m4's `--freeze-state`
+//! (version 1). Each record is a letter followed by comma-separated numbers
+//! and a newline; string payloads follow, concatenated, with their byte
+//! lengths given by the numbers, and are terminated by a newline:
+//!
+//! ```text
+//! # comment
+//! V1                version of the format
+//! Q1,1\n`'\n        quote delimiters
+//! C1,1\n#\n\n       comment delimiters
+//! F6,6\ndefinedefine\n  `define` recognized as the builtin `define`
+//! T3,3\nfoobar\n    `foo` defined as the text `bar`
+//! D0,0\n\n          diversion 0 and its contents
+//! ```
+//!
+//! As in GNU m4, `__gnu__` and `__unix__` are frozen as empty text macros.
+//! GNU m4 lists every one of its builtins; those this crate does not
+//! implement are skipped when reloading.
+
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use crate::processor::{Builtin, Expander, MacroRegistry};
+
+/// Version of the frozen file format written by [`Expander::freeze_state`]
+pub const FROZEN_VERSION: u32 = 1;
+
+const DEFAULT_QUOTES: (&str, &str) = ("`", "'");
+const DEFAULT_COMMENTS: (&str, &str) = ("#", "\n");
+
+/// Failure to reload a frozen state file
+#[derive(Debug, Error)]
+pub enum FrozenError {
+    #[error("frozen file version {0} is not supported (expected {FROZEN_VERSION})")]
+    Version(String),
+    #[error("malformed frozen file at line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("frozen file requires {0}, which is not supported")]
+    Unsupported(String),
+    #[error("failed to read frozen file: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl Expander {
+    /// Write the complete macro state (builtin names and aliases, and text
+    /// definitions) to `out` in GNU m4's frozen file format
+    pub fn freeze_state(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "# This is a frozen state file generated by m4rs {}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(out, "V{}", FROZEN_VERSION)?;
+
+        // Sorted so the same state always freezes to the same bytes
+        let mut builtins: Vec<_> = self.builtins().collect();
+        builtins.sort_unstable_by_key(|&(name, _)| name);
+        let mut definitions: Vec<_> = self.registry.iter().collect();
+        for (name, builtin) in builtins {
+            match builtin {
+                // GNU m4 defines these as text, and freezes them that way
+                Builtin::Gnu | Builtin::Unix => definitions.push((name, "")),
+                _ => write_record(&mut out, 'F', name, builtin.name())?,
+            }
+        }
+
+        definitions.sort_unstable_by_key(|&(name, _)| name);
+        for (name, body) in definitions {
+            write_record(&mut out, 'T', name, body)?;
+        }
+
+        // Current diversion, always standard output
+        write!(out, "D0,0\n\n")?;
+        Ok(())
+    }
+
+    /// Replace the macro state with the contents of a frozen state file.
+    ///
+    /// Every builtin and definition not listed in the file is removed, just as
+    /// if the state had been built from scratch. If the file cannot be
+    /// reloaded the state is left as it was.
+    ///
+    /// Returns the names bound to builtins this crate does not implement,
+    /// such as GNU m4's `format`; they are left undefined.
+    pub fn reload_state(&mut self, mut input: impl Read) -> Result<Vec<String>, FrozenError> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let records = FrozenReader::new(&data).records()?;
+
+        // Nothing changes until every record has been checked
+        let mut builtins = Vec::new();
+        let mut unsupported = Vec::new();
+        let mut registry = MacroRegistry::new();
+        for record in records {
+            match record {
+                Record::Quotes(start, end) => {
+                    if (start.as_str(), end.as_str()) != DEFAULT_QUOTES {
+                        return Err(FrozenError::Unsupported("changed quote delimiters".into()));
+                    }
+                }
+                Record::Comments(start, end) => {
+                    if (start.as_str(), end.as_str()) != DEFAULT_COMMENTS {
+                        return Err(FrozenError::Unsupported(
+                            "changed comment delimiters".into(),
+                        ));
+                    }
+                }
+                Record::Builtin { name, builtin } => match Builtin::from_name(&builtin) {
+                    Some(builtin) => builtins.push((name, builtin)),
+                    None => unsupported.push(name),
+                },
+                Record::Text(name, body) => registry.define_source(name, &body),
+                Record::Diversion(number, text) => {
+                    if number != 0 || !text.is_empty() {
+                        return Err(FrozenError::Unsupported("diversions".into()));
+                    }
+                }
+            }
+        }
+
+        let names: Vec<String> = self.builtins().map(|(name, _)| name.to_owned()).collect();
+        for name in names {
+            self.undefine(&name);
+        }
+        self.registry = registry;
+        for (name, builtin) in builtins {
+            self.define_builtin(name, builtin);
+        }
+        Ok(unsupported)
+    }
+}
+
+fn write_record(out: &mut impl Write, kind: char, first: &str, second: &str) -> io::Result<()> {
+    write!(
+        out,
+        "{}{},{}\n{}{}\n",
+        kind,
+        first.len(),
+        second.len(),
+        first,
+        second
+    )
+}
+
+/// A single record of a frozen file, after the version header
+enum Record {
+    Quotes(String, String),
+    Comments(String, String),
+    Builtin { name: String, builtin: String },
+    Text(String, String),
+    Diversion(i64, String),
+}
+
+/// Cursor over the raw bytes of a frozen file
+struct FrozenReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> FrozenReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            line: 1,
+        }
+    }
+
+    fn records(mut self) -> Result<Vec<Record>, FrozenError> {
+        let mut records = Vec::new();
+        let mut versioned = false;
+
+        while let Some(kind) = self.next_kind() {
+            if !versioned && kind != b'V' {
+                return Err(self.error("missing version record"));
+            }
+            match kind {
+                b'V' => {
+                    let version = self.take_until(b'\n')?;
+                    if version != FROZEN_VERSION.to_string() {
+                        return Err(FrozenError::Version(version));
+                    }
+                    versioned = true;
+                }
+                b'Q' | b'C' | b'F' | b'T' => {
+                    let first_len = self.number(b',')?;
+                    let second_len = self.number(b'\n')?;
+                    let first = self.take(first_len as usize)?;
+                    let second = self.take(second_len as usize)?;
+                    self.expect(b'\n')?;
+                    records.push(match kind {
+                        b'Q' => Record::Quotes(first, second),
+                        b'C' => Record::Comments(first, second),
+                        b'F' => Record::Builtin {
+                            name: first,
+                            builtin: second,
+                        },
+                        _ => Record::Text(first, second),
+                    });
+                }
+                b'D' => {
+                    let number = self.number(b',')?;
+                    let len = self.number(b'\n')?;
+                    let text = self.take(len as usize)?;
+                    self.expect(b'\n')?;
+                    records.push(Record::Diversion(number, text));
+                }
+                other => {
+                    return Err(self.error(format!("unknown record type '{}'", other as char)));
+                }
+            }
+        }
+
+        if !versioned {
+            return Err(self.error("missing version record"));
+        }
+        Ok(records)
+    }
+
+    /// Skip comments and blank lines, returning the next record letter
+    fn next_kind(&mut self) -> Option<u8> {
+        loop {
+            match self.data.get(self.pos)? {
+                b'\n' => self.advance(1),
+                b'#' => {
+                    let end = self.data[self.pos..]
+                        .iter()
+                        .position(|&b| b == b'\n')
+                        .map_or(self.data.len(), |i| self.pos + i + 1);
+                    self.advance(end - self.pos);
+                }
+                &kind => {
+                    self.advance(1);
+                    return Some(kind);
+                }
+            }
+        }
+    }
+
+    /// Parse a decimal number terminated by `terminator`
+    fn number(&mut self, terminator: u8) -> Result<i64, FrozenError> {
+        let text = self.take_until(terminator)?;
+        text.parse()
+            .map_err(|_| self.error(format!("invalid number '{}'", text)))
+    }
+
+    /// Take bytes up to `terminator`, consuming the terminator
+    fn take_until(&mut self, terminator: u8) -> Result<String, FrozenError> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        let text = self.take(len)?;
+        self.advance(1);
+        Ok(text)
+    }
+
+    fn take(&mut self, len: usize) -> Result<String, FrozenError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        let text = std::str::from_utf8(&self.data[self.pos..end])
+            .map_err(|_| self.error("invalid UTF-8"))?
+            .to_owned();
+        self.advance(len);
+        Ok(text)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), FrozenError> {
+        if self.data.get(self.pos) != Some(&byte) {
+            return Err(self.error(format!("expected {:?}", byte as char)));
+        }
+        self.advance(1);
+        Ok(())
+    }
+
+    fn advance(&mut self, len: usize) {
+        let end = self.pos + len;
+        self.line += self.data[self.pos..end]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count();
+        self.pos = end;
+    }
+
+    fn error(&self, message: impl Into<String>) -> FrozenError {
+        FrozenError::Syntax {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn freeze(expander: &Expander) -> String {
+        let mut out = Vec::new();
+        expander.freeze_state(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .load("define(`greet', `Hello $1!')define(`twice', `greet($1) greet($1)')")
+            .unwrap();
+        let mut expander = Expander::new(registry);
+        expander.undefine("ifdef");
+        expander.define_builtin("my_ifelse", Builtin::Ifelse);
+
+        let frozen = freeze(&expander);
+        assert!(frozen.contains("T5,9\ngreetHello $1!\n"));
+        assert!(frozen.contains("T7,0\n__gnu__\n") && !frozen.contains("F7,7\n__gnu__"));
+        assert!(frozen.contains("F9,6\nmy_ifelseifelse\n"));
+
+        let mut reloaded = Expander::new(MacroRegistry::new());
+        reloaded.reload_state(frozen.as_bytes()).unwrap();
+        assert_eq!(reloaded.builtin("ifdef"), None);
+        assert_eq!(reloaded.builtin("my_ifelse"), Some(Builtin::Ifelse));
+        assert_eq!(
+            reloaded
+                .expand("twice(World) my_ifelse(a, a, y, n)")
+                .unwrap(),
+            "Hello World! Hello World! y"
+        );
+        assert_eq!(freeze(&reloaded), frozen);
+    }
+
+    #[test]
+    fn test_reload_gnu_file() {
+        // Every GNU builtin is listed, most of which this crate lacks
+        let frozen = include_str!("../tests/data/gnu-1.4.19.m4f");
+        let mut expander = Expander::new(MacroRegistry::new());
+        let unsupported = expander.reload_state(frozen.as_bytes()).unwrap();
+        assert!(unsupported.iter().any(|name| name == "format"));
+        assert!(
+            !unsupported
+                .iter()
+                .any(|name| name == "define" || name == "eval")
+        );
+        assert!(!expander.is_defined("format"));
+        assert_eq!(expander.builtin("eval"), Some(Builtin::Eval));
+        assert_eq!(expander.builtin("dnl"), None);
+        assert_eq!(expander.builtin("include"), Some(Builtin::Include));
+        assert_eq!(
+            expander
+                .expand("define(`baz', `qux')foo(baz) ifdef(`__gnu__', `gnu')")
+                .unwrap(),
+            "bar qux gnu"
+        );
+    }
+
+    #[test]
+    fn test_reload_errors() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        assert!(matches!(
+            expander.reload_state("V2\n".as_bytes()),
+            Err(FrozenError::Version(v)) if v == "2"
+        ));
+        assert!(matches!(
+            expander.reload_state("T3,3\nfoobar\n".as_bytes()),
+            Err(FrozenError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            expander.reload_state("V1\nT3,30\nfoobar\n".as_bytes()),
+            Err(FrozenError::Syntax { .. })
+        ));
+        assert!(matches!(
+            expander.reload_state("V1\nQ1,1\n[]\n".as_bytes()),
+            Err(FrozenError::Unsupported(_))
+        ));
+        assert!(matches!(
+            expander.reload_state("V1\nD1,3\nabc\n".as_bytes()),
+            Err(FrozenError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_state() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        expander.expand("define(`foo', `bar')").unwrap();
+        let frozen = freeze(&expander);
+
+        // The records before the failing one are not applied either
+        let partial = "V1\nF6,6\ndefinedefine\nT1,1\nxy\nQ1,1\n[]\n";
+        assert!(matches!(
+            expander.reload_state(partial.as_bytes()),
+            Err(FrozenError::Unsupported(_))
+        ));
+        let truncated = "V1\nT1,1\nxy\nF3,5\nfoo";
+        assert!(expander.reload_state(truncated.as_bytes()).is_err());
+
+        assert_eq!(freeze(&expander), frozen);
+        assert_eq!(expander.expand("foo ifdef(`x', y, n)").unwrap(), "bar n");
+    }
+}
@@ -0,0 +1,752 @@
+//! Input stack and tokenizer used by the expander.
+//!
+//! Input is read from a stack of sources: the text being expanded (the
+//! stream) sits at the bottom, and the expansion of every macro call is pushed
+//! on top of it so it is scanned again before the rest of the input, as in m4.
+//! Characters flow across source boundaries transparently; a source is
+//! dropped once it has been read completely. Files pulled in by `include`
+//! are pushed the same way but keep track of their own position.
+//!
+//! More text can be appended to the stream until it is marked finished. Until
+//! then, a token that runs into the end of the stream is not returned, since
+//! later text could still extend it; the reader reports [`Lexed::Blocked`]
+//! and leaves the input untouched so the token can be read again once more
+//! text has arrived.
+
+use crate::error::Unclosed;
+use crate::span::{FileId, Location};
+use crate::word::WordSyntax;
+
+/// Token read from the input stack
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Lexeme {
+    /// Potential macro name
+    Word(String),
+    /// Contents of a quoted string, with the outermost quotes removed
+    Quoted(String),
+    /// Comment, including the `#` and the terminating newline. Empty when
+    /// comments are dropped.
+    Comment(String),
+    /// Run of characters with no special meaning
+    Text(String),
+    /// `(`
+    Open,
+    /// `,`
+    Comma,
+    /// `)`
+    Close,
+}
+
+/// Result of reading from the input stack
+#[derive(Debug, PartialEq)]
+pub(crate) enum Lexed {
+    Token(Lexeme),
+    /// Every source is exhausted and the stream is finished
+    End,
+    /// The stream ran out before the next token was complete
+    Blocked,
+}
+
+/// Result of looking for a word
+enum Scanned {
+    /// The word and the position after it
+    Word(String, Cursor),
+    /// There is no word here
+    Nothing,
+    /// The stream ran out before the end of the word was known
+    Blocked,
+}
+
+/// One source of input text
+#[derive(Default)]
+struct Input {
+    text: String,
+    pos: usize,
+    /// For an included file: the file, and the position in it of the next
+    /// character
+    file: Option<(FileId, Location)>,
+}
+
+/// Position of the next character to read, possibly in a source below the
+/// top one. Sources are numbered from the top of the stack; the stream comes
+/// last.
+#[derive(Clone, Copy)]
+struct Cursor {
+    source: usize,
+    pos: usize,
+}
+
+/// Stack of input sources, read from the top
+pub(crate) struct InputStack {
+    /// Pushed-back text; the last entry is read first
+    pushed: Vec<Input>,
+    /// Text being expanded, read once everything pushed back is exhausted
+    stream: Input,
+    /// No more text will be appended to the stream
+    finished: bool,
+    /// Position in the stream of the next character to read
+    location: Location,
+    /// Number of pushed sources that are included files
+    files: usize,
+    /// Comments are dropped, as in this crate's 0.1
+    drop_comments: bool,
+    /// The last token was text ending in something other than whitespace,
+    /// which a `#` continues when comments are dropped
+    literal: bool,
+    /// Text read from the stream is kept in `recorded`
+    recording: bool,
+    recorded: String,
+}
+
+impl Default for InputStack {
+    fn default() -> Self {
+        Self {
+            pushed: Vec::new(),
+            stream: Input::default(),
+            finished: false,
+            location: Location::START,
+            files: 0,
+            drop_comments: false,
+            literal: false,
+            recording: false,
+            recorded: String::new(),
+        }
+    }
+}
+
+/// Whether `c` can start a macro name
+pub(crate) fn is_word_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+/// Whether `c` can continue a macro name
+pub(crate) fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whitespace skipped before macro arguments
+pub(crate) fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0C' | '\x0B')
+}
+
+/// Characters that start a token other than a word or text
+fn is_structural(c: char) -> bool {
+    matches!(c, '`' | '#' | '(' | ',' | ')')
+}
+
+/// Characters that end a run of plain text
+fn is_special(c: char) -> bool {
+    is_word_start(c) || is_structural(c)
+}
+
+impl InputStack {
+    /// Append text to the end of the stream
+    pub(crate) fn append(&mut self, text: &str) {
+        // Forget what has been read so the stream only holds unread text
+        self.stream.text.drain(..self.stream.pos);
+        self.stream.pos = 0;
+        self.stream.text.push_str(text);
+    }
+
+    /// Mark the stream finished: its end is the end of the input
+    pub(crate) fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// Read comments the way this crate's 0.1 did: a `#` that starts a
+    /// token begins a comment that runs up to the newline, or the end of
+    /// the text it is in, and is dropped. A `#` inside a run of text, as in
+    /// `$#`, is text.
+    pub(crate) fn drop_comments(&mut self, enabled: bool) {
+        self.drop_comments = enabled;
+    }
+
+    /// Whether the stream has been marked finished
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Text appended to the stream that has not been read yet
+    pub(crate) fn unread(&self) -> &str {
+        &self.stream.text[self.stream.pos..]
+    }
+
+    /// Push text to be read before everything already on the stack
+    pub(crate) fn push(&mut self, text: String) {
+        if !text.is_empty() {
+            self.pushed.push(Input {
+                text,
+                pos: 0,
+                file: None,
+            });
+        }
+    }
+
+    /// Push the text of an included file, to be read like [`InputStack::push`]
+    /// but with positions counted from the start of `file`
+    pub(crate) fn push_file(&mut self, text: String, file: FileId) {
+        if !text.is_empty() {
+            self.pushed.push(Input {
+                text,
+                pos: 0,
+                file: Some((file, Location::START)),
+            });
+            self.files += 1;
+        }
+    }
+
+    fn source(&self, index: usize) -> &Input {
+        match self.pushed.len().checked_sub(index + 1) {
+            Some(i) => &self.pushed[i],
+            None => &self.stream,
+        }
+    }
+
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            source: 0,
+            pos: self.source(0).pos,
+        }
+    }
+
+    /// Character at `cursor`, advancing past it. `None` at the end of all
+    /// text currently available.
+    fn read(&self, cursor: &mut Cursor) -> Option<char> {
+        loop {
+            let input = self.source(cursor.source);
+            if let Some(c) = input.text[cursor.pos..].chars().next() {
+                cursor.pos += c.len_utf8();
+                return Some(c);
+            }
+            if cursor.source >= self.pushed.len() {
+                return None;
+            }
+            cursor.source += 1;
+            cursor.pos = self.source(cursor.source).pos;
+        }
+    }
+
+    /// Consume everything before `cursor`
+    fn commit(&mut self, cursor: Cursor) {
+        // Sources above the cursor have been read completely
+        if cursor.source < self.pushed.len() {
+            let len = self.pushed.len() - cursor.source;
+            self.drop_pushed(len);
+            let input = &mut self.pushed[len - 1];
+            if let Some((_, location)) = &mut input.file {
+                *location = location.advance(&input.text[input.pos..cursor.pos]);
+            }
+            input.pos = cursor.pos;
+        } else {
+            self.drop_pushed(0);
+            self.advance_stream(cursor.pos);
+        }
+        while let Some(input) = self.pushed.last()
+            && input.pos >= input.text.len()
+        {
+            self.drop_pushed(self.pushed.len() - 1);
+        }
+    }
+
+    /// Drop pushed sources so `len` remain
+    fn drop_pushed(&mut self, len: usize) {
+        if self.files > 0 {
+            self.files -= self.pushed[len..]
+                .iter()
+                .filter(|input| input.file.is_some())
+                .count();
+        }
+        self.pushed.truncate(len);
+    }
+
+    /// Move the stream's read position forward to `pos`, keeping track of
+    /// lines and columns
+    fn advance_stream(&mut self, pos: usize) {
+        let read = &self.stream.text[self.stream.pos..pos];
+        self.location = self.location.advance(read);
+        if self.recording {
+            self.recorded.push_str(read);
+        }
+        self.stream.pos = pos;
+    }
+
+    /// Position of the next character to be read from the stream, or from
+    /// the innermost included file being read. Text pushed back on top of
+    /// either counts as being at that position.
+    pub(crate) fn location(&self) -> Location {
+        self.included()
+            .map_or(self.location, |(_, location)| location)
+    }
+
+    /// Included file [`InputStack::location`] is in, or `None` for the
+    /// stream
+    pub(crate) fn file(&self) -> Option<FileId> {
+        self.included().map(|(file, _)| file)
+    }
+
+    fn included(&self) -> Option<(FileId, Location)> {
+        if self.files == 0 {
+            return None;
+        }
+        self.pushed.iter().rev().find_map(|input| input.file)
+    }
+
+    /// Keep the text read from the stream from now on, for
+    /// [`InputStack::recorded`]
+    pub(crate) fn record(&mut self) {
+        self.recording = true;
+    }
+
+    /// Text read from the stream since recording started or was last
+    /// forgotten, as it was written. Pushed-back text is not included.
+    pub(crate) fn recorded(&self) -> &str {
+        &self.recorded
+    }
+
+    /// Drop the text recorded so far
+    pub(crate) fn forget_recorded(&mut self) {
+        self.recorded.clear();
+    }
+
+    /// Whether the next character comes from the stream rather than from
+    /// pushed-back text
+    pub(crate) fn reading_stream(&self) -> bool {
+        self.pushed.is_empty()
+    }
+
+    /// Position of the end of the text appended to the stream so far
+    pub(crate) fn end_location(&self) -> Location {
+        self.location.advance(self.unread())
+    }
+
+    /// Consume everything available, returning it as it was read
+    pub(crate) fn take_rest(&mut self) -> String {
+        std::iter::from_fn(|| self.next_char()).collect()
+    }
+
+    /// Next character without consuming it, or `None` if no text is
+    /// available right now
+    pub(crate) fn peek(&self) -> Option<char> {
+        self.read(&mut self.cursor())
+    }
+
+    /// Consume the next character
+    pub(crate) fn next_char(&mut self) -> Option<char> {
+        let mut cursor = self.cursor();
+        let c = self.read(&mut cursor)?;
+        self.commit(cursor);
+        Some(c)
+    }
+
+    /// Consume whitespace. Returns `false` if the stream ran out before
+    /// anything else was seen.
+    pub(crate) fn skip_whitespace(&mut self) -> bool {
+        loop {
+            match self.peek() {
+                Some(c) if is_whitespace(c) => {
+                    self.next_char();
+                }
+                Some(_) => return true,
+                None => return self.finished,
+            }
+        }
+    }
+
+    /// Consume characters up to and including the next newline. Returns
+    /// `false` if the stream ran out first.
+    pub(crate) fn skip_line(&mut self) -> bool {
+        loop {
+            match self.next_char() {
+                Some('\n') => return true,
+                Some(_) => {}
+                None => return self.finished,
+            }
+        }
+    }
+
+    /// Longest word `words` recognizes at `cursor`
+    fn read_word(&self, words: &mut WordSyntax, cursor: Cursor) -> Scanned {
+        let mut scan = words.start();
+        let mut name = String::new();
+        let mut ahead = cursor;
+        let mut end = None;
+        loop {
+            let before = ahead;
+            match self.read(&mut ahead) {
+                Some(c) => {
+                    let alive = words.push(&mut scan, c);
+                    if scan.matched() == Some(name.len()) {
+                        end = Some((before, name.len()));
+                    }
+                    if !alive {
+                        break;
+                    }
+                    name.push(c);
+                }
+                None if self.finished => {
+                    words.finish(&mut scan);
+                    if scan.matched() == Some(name.len()) {
+                        end = Some((before, name.len()));
+                    }
+                    break;
+                }
+                // The word may continue, or be followed by arguments
+                None => return Scanned::Blocked,
+            }
+        }
+        match end {
+            Some((cursor, len)) => {
+                name.truncate(len);
+                Scanned::Word(name, cursor)
+            }
+            None => Scanned::Nothing,
+        }
+    }
+
+    /// Read the next token, taking words as `words` describes if given.
+    /// Fails if the finished stream ends inside a quoted string or a
+    /// comment; nothing is consumed then.
+    pub(crate) fn next_token(
+        &mut self,
+        mut words: Option<&mut WordSyntax>,
+    ) -> Result<Lexed, Unclosed> {
+        let mut cursor = self.cursor();
+        let Some(c) = self.read(&mut cursor) else {
+            return Ok(if self.finished {
+                Lexed::End
+            } else {
+                Lexed::Blocked
+            });
+        };
+
+        if let Some(words) = words.as_deref_mut()
+            && !is_structural(c)
+        {
+            match self.read_word(words, self.cursor()) {
+                Scanned::Word(name, end) => {
+                    self.commit(end);
+                    self.literal = false;
+                    return Ok(Lexed::Token(Lexeme::Word(name)));
+                }
+                Scanned::Blocked => return Ok(Lexed::Blocked),
+                Scanned::Nothing => {}
+            }
+        }
+
+        let lexeme = match c {
+            '(' => Lexeme::Open,
+            ',' => Lexeme::Comma,
+            ')' => Lexeme::Close,
+            '`' => {
+                let mut text = String::new();
+                let mut nesting = 1;
+                loop {
+                    // Copy the text up to the next quote in one go
+                    let rest = &self.source(cursor.source).text[cursor.pos..];
+                    let plain = rest.find(['`', '\'']).unwrap_or(rest.len());
+                    text.push_str(&rest[..plain]);
+                    cursor.pos += plain;
+                    let Some(c) = self.read(&mut cursor) else {
+                        if self.finished {
+                            return Err(Unclosed::String);
+                        }
+                        return Ok(Lexed::Blocked);
+                    };
+                    match c {
+                        '`' => nesting += 1,
+                        '\'' => {
+                            nesting -= 1;
+                            if nesting == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    text.push(c);
+                }
+                Lexeme::Quoted(text)
+            }
+            '#' if self.drop_comments && !self.literal => {
+                let source = cursor.source;
+                loop {
+                    let mut ahead = cursor;
+                    match self.read(&mut ahead) {
+                        // The comment ends with the text it is in
+                        Some(c) if c != '\n' && ahead.source == source => cursor = ahead,
+                        Some(_) => break,
+                        None if self.finished => break,
+                        None => return Ok(Lexed::Blocked),
+                    }
+                }
+                Lexeme::Comment(String::new())
+            }
+            '#' if !self.drop_comments => {
+                let mut text = String::from(c);
+                loop {
+                    match self.read(&mut cursor) {
+                        Some(c) => {
+                            text.push(c);
+                            if c == '\n' {
+                                break;
+                            }
+                        }
+                        None if self.finished => return Err(Unclosed::Comment),
+                        None => return Ok(Lexed::Blocked),
+                    }
+                }
+                Lexeme::Comment(text)
+            }
+            c if words.is_none() && is_word_start(c) => {
+                let mut name = String::from(c);
+                loop {
+                    let mut ahead = cursor;
+                    match self.read(&mut ahead) {
+                        Some(c) if is_word_char(c) => {
+                            name.push(c);
+                            cursor = ahead;
+                        }
+                        Some(_) => break,
+                        None if self.finished => break,
+                        // The name may continue, or be followed by arguments
+                        None => return Ok(Lexed::Blocked),
+                    }
+                }
+                Lexeme::Word(name)
+            }
+            c => {
+                // Plain text may be split anywhere, so it never waits for input
+                let mut text = String::from(c);
+                let drop_comments = self.drop_comments;
+                let mut special = |c, text: &str| match words.as_deref_mut() {
+                    _ if c == '#' && drop_comments => text.ends_with(is_whitespace),
+                    Some(words) => is_structural(c) || words.can_start(c),
+                    None => is_special(c),
+                };
+                loop {
+                    let mut ahead = cursor;
+                    match self.read(&mut ahead) {
+                        Some(c) if !special(c, &text) => {
+                            text.push(c);
+                            cursor = ahead;
+                        }
+                        _ => break,
+                    }
+                }
+                Lexeme::Text(text)
+            }
+        };
+        self.commit(cursor);
+        self.literal = matches!(&lexeme, Lexeme::Text(text) if !text.ends_with(is_whitespace));
+        Ok(Lexed::Token(lexeme))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished(text: &str) -> InputStack {
+        let mut stack = InputStack::default();
+        stack.append(text);
+        stack.finish();
+        stack
+    }
+
+    fn tokens(stack: &mut InputStack) -> Vec<Lexeme> {
+        std::iter::from_fn(|| match stack.next_token(None) {
+            Ok(Lexed::Token(lexeme)) => Some(lexeme),
+            _ => None,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            tokens(&mut finished("foo(`a `b'', 1x) # c\nd")),
+            vec![
+                Lexeme::Word("foo".into()),
+                Lexeme::Open,
+                Lexeme::Quoted("a `b'".into()),
+                Lexeme::Comma,
+                Lexeme::Text(" 1".into()),
+                Lexeme::Word("x".into()),
+                Lexeme::Close,
+                Lexeme::Text(" ".into()),
+                Lexeme::Comment("# c\n".into()),
+                Lexeme::Word("d".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated() {
+        assert_eq!(finished("`abc").next_token(None), Err(Unclosed::String));
+        assert_eq!(finished("# abc").next_token(None), Err(Unclosed::Comment));
+
+        let mut stack = finished("ab `c");
+        tokens(&mut stack);
+        // The quote is left unread, and the end is after it
+        assert_eq!(stack.unread(), "`c");
+        assert_eq!(stack.end_location().offset, 5);
+    }
+
+    #[test]
+    fn test_pushback_spans_sources() {
+        let mut stack = finished("cd more' end");
+        stack.push("x `q".to_string());
+        stack.push("ab".to_string());
+
+        // Tokens continue from pushed-back text into the text below it
+        assert_eq!(
+            tokens(&mut stack),
+            vec![
+                Lexeme::Word("abx".into()),
+                Lexeme::Text(" ".into()),
+                Lexeme::Quoted("qcd more".into()),
+                Lexeme::Text(" ".into()),
+                Lexeme::Word("end".into()),
+            ]
+        );
+        assert_eq!(stack.next_token(None).unwrap(), Lexed::End);
+    }
+
+    #[test]
+    fn test_blocked_until_more_input() {
+        let mut stack = InputStack::default();
+        stack.append("ab `c");
+        stack.push("x".to_string());
+        assert_eq!(
+            stack.next_token(None).unwrap(),
+            Lexed::Token(Lexeme::Word("xab".into()))
+        );
+        assert_eq!(
+            stack.next_token(None).unwrap(),
+            Lexed::Token(Lexeme::Text(" ".into()))
+        );
+        // The quote is unfinished; nothing is consumed
+        assert_eq!(stack.next_token(None).unwrap(), Lexed::Blocked);
+        assert_eq!(stack.unread(), "`c");
+
+        stack.append("d' wor");
+        assert_eq!(
+            stack.next_token(None).unwrap(),
+            Lexed::Token(Lexeme::Quoted("cd".into()))
+        );
+        assert_eq!(
+            stack.next_token(None).unwrap(),
+            Lexed::Token(Lexeme::Text(" ".into()))
+        );
+        assert_eq!(stack.next_token(None).unwrap(), Lexed::Blocked);
+
+        stack.finish();
+        assert_eq!(
+            stack.next_token(None).unwrap(),
+            Lexed::Token(Lexeme::Word("wor".into()))
+        );
+        assert_eq!(stack.next_token(None).unwrap(), Lexed::End);
+    }
+
+    #[test]
+    fn test_custom_words() {
+        let mut words = WordSyntax::new("@[A-Z]+@").unwrap();
+        let mut stack = InputStack::default();
+        stack.append("a @NA");
+        assert_eq!(
+            stack.next_token(Some(&mut words)).unwrap(),
+            Lexed::Token(Lexeme::Text("a ".into()))
+        );
+        // The word may still end with `@`
+        assert_eq!(stack.next_token(Some(&mut words)).unwrap(), Lexed::Blocked);
+        stack.append("ME@(@x");
+        stack.finish();
+        let mut tokens = Vec::new();
+        while let Ok(Lexed::Token(lexeme)) = stack.next_token(Some(&mut words)) {
+            tokens.push(lexeme);
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Lexeme::Word("@NAME@".into()),
+                Lexeme::Open,
+                Lexeme::Text("@x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recorded() {
+        let mut stack = finished("ab `c' d");
+        stack.record();
+        stack.next_token(None).unwrap();
+        stack.push("pushed".to_string());
+        assert!(!stack.reading_stream());
+        tokens(&mut stack);
+        // Only text from the stream is kept, quotes and all
+        assert_eq!(stack.recorded(), "ab `c' d");
+        stack.forget_recorded();
+        assert_eq!(stack.recorded(), "");
+    }
+
+    #[test]
+    fn test_location() {
+        let mut stack = finished("ab\ncé d");
+        assert_eq!(stack.location(), Location::START);
+        stack.next_token(None).unwrap();
+        stack.push("pushed\n".to_string());
+        stack.next_token(None).unwrap();
+        // Pushed-back text does not move the position
+        assert_eq!(
+            stack.location(),
+            Location {
+                offset: 2,
+                line: 1,
+                column: 3
+            }
+        );
+        tokens(&mut stack);
+        assert_eq!(
+            stack.location(),
+            Location {
+                offset: 8,
+                line: 2,
+                column: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_included_location() {
+        let mut stack = finished("ab\ncd");
+        stack.next_token(None).unwrap();
+        stack.push_file("x\ny ".to_string(), FileId(3));
+        stack.push("pushed".to_string());
+        // Text pushed on top of an included file counts as being in it
+        assert_eq!(stack.file(), Some(FileId(3)));
+        assert_eq!(stack.location(), Location::START);
+        // `pushedx`, then the newline
+        stack.next_token(None).unwrap();
+        stack.next_token(None).unwrap();
+        assert_eq!(
+            stack.location(),
+            Location {
+                offset: 2,
+                line: 2,
+                column: 1
+            }
+        );
+        // Back in the stream once the file has been read: `y`, then text
+        // running on into the stream
+        stack.next_token(None).unwrap();
+        stack.next_token(None).unwrap();
+        assert_eq!(stack.file(), None);
+        assert_eq!(
+            stack.location(),
+            Location {
+                offset: 3,
+                line: 2,
+                column: 1
+            }
+        );
+    }
+}
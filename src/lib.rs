@@ -1,3 +1,14 @@
 pub mod ast;
+pub mod diagnostic;
+pub mod error;
+mod eval;
+pub mod frozen;
+mod lexer;
 pub mod parser;
 pub mod processor;
+pub mod sandbox;
+pub mod span;
+pub mod trace;
+pub mod vfs;
+pub mod warning;
+pub mod word;
@@ -1,95 +1,230 @@
 use std::borrow::Cow;
 
-use pest::{Parser, iterators::Pairs};
+use pest::error::{Error, ErrorVariant, InputLocation};
+use pest::iterators::{Pair, Pairs};
+use pest::{Parser, Position};
 use pest_derive::Parser;
 
 use crate::ast::{Group, MacroCall, Token};
+use crate::error::Unclosed;
 
 #[derive(Parser)]
 #[grammar = "src/m4.pest"]
 pub struct M4Parser;
 
-impl M4Parser {
-    /// Parse M4 input into a list of tokens
-    pub fn parse_input<'a>(input: &'a str) -> Result<Vec<Token<'a>>, pest::error::Error<Rule>> {
-        let mut pairs: Pairs<'a, Rule> = M4Parser::parse(Rule::file, input)?;
-        let file = pairs.next().expect("parser returned no file rule");
+/// The text being parsed, for placing errors in it
+struct Source<'a> {
+    input: &'a str,
+}
 
-        Ok(file
-            .into_inner()
-            .filter_map(Self::parse_token)
-            .collect::<Vec<_>>())
+impl<'a> Source<'a> {
+    /// Byte offset of `text`, which must be a slice of the input
+    fn offset(&self, text: &str) -> usize {
+        text.as_ptr() as usize - self.input.as_ptr() as usize
+    }
+
+    /// Move an error from parsing `text`, a slice of the input, to the
+    /// same place in the input
+    fn relocate(&self, error: Error<Rule>, text: &str) -> Box<Error<Rule>> {
+        let start = self.offset(text);
+        let position = |offset| {
+            Position::new(self.input, start + offset).expect("error is inside the parsed text")
+        };
+        Box::new(match error.location {
+            InputLocation::Pos(offset) => Error::new_from_pos(error.variant, position(offset)),
+            InputLocation::Span((from, to)) => {
+                Error::new_from_span(error.variant, position(from).span(&position(to)))
+            }
+        })
+    }
+
+    /// Error for a construct opened at `offset` and still open at the end
+    /// of the input. It spans everything from the opening to the end.
+    fn unclosed(&self, construct: Unclosed, offset: usize) -> Box<Error<Rule>> {
+        let span = pest::Span::new(self.input, offset, self.input.len())
+            .expect("offset is inside the input");
+        Box::new(Error::new_from_span(
+            ErrorVariant::CustomError {
+                message: format!("end of file in {}", construct),
+            },
+            span,
+        ))
     }
+}
 
-    fn parse_token(pair: pest::iterators::Pair<Rule>) -> Option<Token> {
-        match pair.as_rule() {
-            Rule::token => {
-                let inner = pair.into_inner().next()?;
-                Self::parse_token(inner)
+/// The quoted string or comment still open at the end of `input`, if any,
+/// with the offset it was opened at. As for the expander, quotes in
+/// comments and `#` in quotes are text.
+fn unclosed_construct(input: &str) -> Option<(Unclosed, usize)> {
+    let mut depth = 0usize;
+    let mut opened = 0;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '`' => {
+                if depth == 0 {
+                    opened = i;
+                }
+                depth += 1;
+            }
+            '\'' => depth = depth.saturating_sub(1),
+            // Skips the comment, unless it is still open at the end
+            '#' if depth == 0 && !chars.any(|(_, c)| c == '\n') => {
+                return Some((Unclosed::Comment, i));
             }
+            _ => {}
+        }
+    }
+    (depth > 0).then_some((Unclosed::String, opened))
+}
+
+impl M4Parser {
+    /// Parse M4 input into a list of tokens. Comments run from `#` to the
+    /// end of the line, as in the GNU dialect, and are kept as literal text.
+    /// Input that ends inside a quoted string, a comment or the arguments of
+    /// a macro call is an error spanning from where that was opened.
+    pub fn parse_input<'a>(input: &'a str) -> Result<Vec<Token<'a>>, Box<Error<Rule>>> {
+        let source = Source { input };
+        let pairs = match M4Parser::parse(Rule::file, input) {
+            Ok(pairs) => pairs,
+            Err(error) => {
+                return Err(match unclosed_construct(input) {
+                    Some((construct, offset)) => source.unclosed(construct, offset),
+                    None => Box::new(error),
+                });
+            }
+        };
+
+        // Arguments without their closing parenthesis do not parse as such,
+        // leaving a call without arguments followed by the opening one.
+        // Quoted text is not parsed here, so calls in it are not seen.
+        let unclosed_call = pairs.clone().flatten().find(|pair| {
+            pair.as_rule() == Rule::macrocall
+                && pair.clone().into_inner().nth(1).is_none()
+                && input[pair.as_span().end()..].starts_with('(')
+        });
+        if let Some(call) = unclosed_call {
+            return Err(source.unclosed(Unclosed::ArgumentList, call.as_span().start()));
+        }
+
+        Self::parse_file(pairs, &source)
+    }
+
+    /// Parse `text`, a slice of the input of `source`
+    fn parse_tokens<'a>(
+        text: &'a str,
+        source: &Source<'a>,
+    ) -> Result<Vec<Token<'a>>, Box<Error<Rule>>> {
+        let pairs = M4Parser::parse(Rule::file, text).map_err(|e| source.relocate(e, text))?;
+        Self::parse_file(pairs, source)
+    }
+
+    fn parse_file<'a>(
+        mut pairs: Pairs<'a, Rule>,
+        source: &Source<'a>,
+    ) -> Result<Vec<Token<'a>>, Box<Error<Rule>>> {
+        let file = pairs.next().expect("parser returned no file rule");
+        let mut tokens = Vec::new();
+        for pair in file.into_inner() {
+            tokens.extend(Self::parse_token(pair, source)?);
+        }
+        Ok(tokens)
+    }
+
+    fn parse_token<'a>(
+        pair: Pair<'a, Rule>,
+        source: &Source<'a>,
+    ) -> Result<Option<Token<'a>>, Box<Error<Rule>>> {
+        Ok(match pair.as_rule() {
+            Rule::token => match pair.into_inner().next() {
+                Some(inner) => Self::parse_token(inner, source)?,
+                None => None,
+            },
             Rule::positional_argument => {
                 let num: usize = pair.as_str()[1..].parse().unwrap_or(0);
                 Some(Token::Positional(num))
             }
-            Rule::macrocall => Self::parse_macrocall(pair).map(Token::MacroCall),
-            Rule::quoted_group => Self::parse_group(pair).map(Token::Group),
-            Rule::literal | Rule::WHITESPACE => Some(Token::Literal(Cow::Borrowed(pair.as_str()))),
+            Rule::macrocall => Self::parse_macrocall(pair, source)?.map(Token::MacroCall),
+            Rule::quoted_group => Some(Token::Group(Self::parse_group(pair, source)?)),
+            Rule::literal | Rule::comment | Rule::WHITESPACE => {
+                Some(Token::Literal(Cow::Borrowed(pair.as_str())))
+            }
             _ => None,
-        }
+        })
     }
 
-    fn parse_macrocall(pair: pest::iterators::Pair<Rule>) -> Option<MacroCall> {
+    fn parse_macrocall<'a>(
+        pair: Pair<'a, Rule>,
+        source: &Source<'a>,
+    ) -> Result<Option<MacroCall<'a>>, Box<Error<Rule>>> {
         let mut inner = pair.into_inner();
 
-        let name = inner.next().map(|p| Cow::Borrowed(p.as_str()))?;
-        let args = inner.next().map(Self::parse_arguments).unwrap_or_default();
+        let Some(name) = inner.next().map(|p| Cow::Borrowed(p.as_str())) else {
+            return Ok(None);
+        };
+        let args = match inner.next() {
+            Some(p) => Self::parse_arguments(p, source)?,
+            None => Vec::new(),
+        };
 
-        Some(MacroCall { name, args })
+        Ok(Some(MacroCall { name, args }))
     }
 
-    fn parse_arguments(pair: pest::iterators::Pair<Rule>) -> Vec<Token> {
-        pair.into_inner()
-            .find(|p| p.as_rule() == Rule::argument_list)
-            .into_iter()
-            .flat_map(|arg_list| {
-                arg_list.into_inner().filter_map(|p| match p.as_rule() {
-                    Rule::argument => Self::parse_argument(p),
-                    _ => None,
-                })
-            })
-            .collect()
+    fn parse_arguments<'a>(
+        pair: Pair<'a, Rule>,
+        source: &Source<'a>,
+    ) -> Result<Vec<Token<'a>>, Box<Error<Rule>>> {
+        let mut args = Vec::new();
+        for arg_list in pair
+            .into_inner()
+            .filter(|p| p.as_rule() == Rule::argument_list)
+        {
+            for p in arg_list.into_inner() {
+                if p.as_rule() == Rule::argument {
+                    args.extend(Self::parse_argument(p, source)?);
+                }
+            }
+        }
+        Ok(args)
     }
 
-    fn parse_argument(pair: pest::iterators::Pair<Rule>) -> Option<Token> {
+    fn parse_argument<'a>(
+        pair: Pair<'a, Rule>,
+        source: &Source<'a>,
+    ) -> Result<Option<Token<'a>>, Box<Error<Rule>>> {
         let lexeme = pair.as_str();
-        let tokens: Vec<Token> = pair.into_inner().filter_map(Self::parse_token).collect();
+        let mut tokens = Vec::new();
+        for p in pair.into_inner() {
+            tokens.extend(Self::parse_token(p, source)?);
+        }
 
         // If there's exactly one token, return it directly
         if tokens.len() == 1 {
-            return tokens.into_iter().next();
+            return Ok(tokens.pop());
         }
 
         // Multiple tokens -> wrap in a Group
-        Some(Token::Group(Group {
+        Ok(Some(Token::Group(Group {
             lexeme: Cow::Borrowed(lexeme),
             tokens,
-        }))
+        })))
     }
 
-    fn parse_group(pair: pest::iterators::Pair<Rule>) -> Option<Group> {
+    fn parse_group<'a>(
+        pair: Pair<'a, Rule>,
+        source: &Source<'a>,
+    ) -> Result<Group<'a>, Box<Error<Rule>>> {
         let lexeme = pair.as_str();
         let content = lexeme
             .strip_prefix('`')
             .and_then(|t| t.strip_suffix('\''))
             .unwrap_or("");
 
-        match M4Parser::parse_input(content) {
-            Ok(tokens) => Some(Group {
-                lexeme: Cow::Borrowed(lexeme),
-                tokens,
-            }),
-            Err(_) => None,
-        }
+        // The content is a slice of the input, so its errors are placed in it
+        Ok(Group {
+            lexeme: Cow::Borrowed(lexeme),
+            tokens: Self::parse_tokens(content, source)?,
+        })
     }
 }
 
@@ -226,4 +361,56 @@ mod tests {
             _ => panic!("Expected MacroCall token for ifelse"),
         }
     }
+
+    #[test]
+    fn test_unclosed() {
+        let message = |input| {
+            let error = M4Parser::parse_input(input).unwrap_err();
+            let InputLocation::Span((start, end)) = error.location else {
+                panic!("expected a span, got {:?}", error.location);
+            };
+            (error.variant.message().into_owned(), start, end)
+        };
+        assert_eq!(
+            message("a `b `c' d"),
+            ("end of file in string".into(), 2, 10)
+        );
+        assert_eq!(
+            message("x f(`a'"),
+            ("end of file in argument list".into(), 2, 7)
+        );
+        assert_eq!(message("x f(`a"), ("end of file in string".into(), 4, 6));
+        assert_eq!(
+            message("x f(a, g(b)"),
+            ("end of file in argument list".into(), 2, 11)
+        );
+        assert_eq!(
+            message("x f(a, g(b)\n"),
+            ("end of file in argument list".into(), 2, 12)
+        );
+
+        assert_eq!(message("`a' # b"), ("end of file in comment".into(), 4, 7));
+
+        // Quoted text is not parsed for calls
+        assert!(M4Parser::parse_input("`f(a' g(b)").is_ok());
+    }
+
+    #[test]
+    fn test_comments() {
+        // Quotes in a comment are text, as the expander reads them
+        let tokens = M4Parser::parse_input("a # `b'\nc").unwrap();
+        assert!(tokens.contains(&Token::Literal(Cow::Borrowed("# `b'\n"))));
+
+        let tokens = M4Parser::parse_input("f(a # `b\n)").unwrap();
+        let [Token::MacroCall(call)] = tokens.as_slice() else {
+            panic!("Expected a single MacroCall token, got {:?}", tokens);
+        };
+        assert_eq!(call.args.len(), 1);
+        assert_eq!(call.args[0].to_string(), "a # `b\n");
+
+        let mut expander = crate::processor::Expander::new(Default::default())
+            .dialect(crate::processor::Dialect::Gnu);
+        let output = expander.expand("define(`f', `[$1]')f(a # `b\n)").unwrap();
+        assert_eq!(output, "[a # `b\n]");
+    }
 }
@@ -1,12 +1,33 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use crate::ast::*;
-use crate::parser::M4Parser;
-
-/// Registry of macro definitions (stores raw, unexpanded tokens)
+use crate::diagnostic::SourceMap;
+use crate::error::{CallSite, Limit, M4Error, Progress, Unclosed};
+use crate::eval;
+use crate::lexer::{InputStack, Lexed, Lexeme, is_word_char, is_word_start};
+use crate::sandbox::{Access, BuiltinCategory, SandboxPolicy};
+use crate::span::{FileId, Location, Span};
+use crate::trace::{Trace, TraceSink};
+use crate::vfs::{FileProvider, RealFs};
+use crate::warning::{Warning, WarningKind, WarningSink};
+use crate::word::WordSyntax;
+
+/// Registry of macro definitions (stores raw, unexpanded body text)
 #[derive(Debug, Default, Clone)]
-pub struct MacroRegistry(HashMap<String, Vec<Token<'static>>>);
+pub struct MacroRegistry(HashMap<String, Definition>);
+
+#[derive(Debug, Clone)]
+struct Definition {
+    body: String,
+    /// Where the definition was made, if known
+    span: Option<Span>,
+}
 
 impl MacroRegistry {
     pub fn new() -> Self {
@@ -15,7 +36,7 @@ impl MacroRegistry {
 
     /// Load macro definitions from M4 source text.
     /// Expands the source - define() calls populate the registry as a side effect.
-    pub fn load(&mut self, source: &str) -> Result<(), String> {
+    pub fn load(&mut self, source: &str) -> Result<(), M4Error> {
         let mut expander = Expander::new(self.clone());
         expander.expand(source)?;
         *self = expander.into_registry();
@@ -23,40 +44,887 @@ impl MacroRegistry {
     }
 
     /// Load macro definitions from a file
-    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
-        let source =
-            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<(), M4Error> {
+        self.load_file_from(&RealFs, path)
+    }
+
+    /// Load macro definitions from a file provided by `files`
+    pub fn load_file_from(
+        &mut self,
+        files: &dyn FileProvider,
+        path: impl AsRef<Path>,
+    ) -> Result<(), M4Error> {
+        let path = path.as_ref();
+        let source = files.read(path).map_err(|source| M4Error::Io {
+            path: path.to_owned(),
+            source,
+        })?;
         self.load(&source)
     }
 
-    /// Register a macro definition (takes owned tokens)
+    /// Register a macro definition from body tokens, stored as their source text
     pub fn define(&mut self, name: String, body: Vec<Token<'static>>) {
-        self.0.insert(name, body);
+        let body = body.iter().map(ToString::to_string).collect();
+        self.0.insert(name, Definition { body, span: None });
     }
 
-    /// Get a macro definition by name
-    pub fn get(&self, name: &str) -> Option<&Vec<Token<'static>>> {
-        self.0.get(name)
+    /// Register a macro definition from its body source text, as `define` would
+    /// store it
+    pub fn define_source(&mut self, name: String, body: &str) {
+        let body = body.to_owned();
+        self.0.insert(name, Definition { body, span: None });
+    }
+
+    /// Register a macro definition from its body source text, made at `span`
+    pub fn define_source_at(&mut self, name: String, body: &str, span: Span) {
+        let body = body.to_owned();
+        let span = Some(span);
+        self.0.insert(name, Definition { body, span });
+    }
+
+    /// Get a macro body by name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|d| d.body.as_str())
+    }
+
+    /// Where a macro was defined, if it is defined and that is known
+    pub fn definition_span(&self, name: &str) -> Option<Span> {
+        self.0.get(name).and_then(|d| d.span)
+    }
+
+    /// Remove a macro definition, returning its body if it was defined
+    pub fn undefine(&mut self, name: &str) -> Option<String> {
+        self.0.remove(name).map(|d| d.body)
     }
 
     /// Check if a macro is defined
     pub fn is_defined(&self, name: &str) -> bool {
         self.0.contains_key(name)
     }
+
+    /// Number of macros defined
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no macros are defined
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over all definitions, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, d)| (name.as_str(), d.body.as_str()))
+    }
+}
+
+/// Macros implemented by the expander itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Builtin {
+    Define,
+    Ifelse,
+    Ifdef,
+    Dnl,
+    /// `builtin(name, args...)`: call a builtin even if it was renamed or
+    /// undefined
+    Builtin,
+    /// `m4exit(status)`: stop expanding
+    Exit,
+    /// `include(file)`: read a file in place of the call
+    Include,
+    /// `sinclude(file)`: like `include`, but a missing file is ignored
+    Sinclude,
+    /// `paste(file)`: output a file as it is, without rescanning it (BSD)
+    Paste,
+    /// `spaste(file)`: like `paste`, but a missing file is ignored (BSD)
+    Spaste,
+    /// `eval(expression, radix, width)`: evaluate an integer expression,
+    /// written in `radix` with at least `width` digits
+    Eval,
+    /// `expr`: another name for `eval` (BSD)
+    Expr,
+    /// `m4wrap(text)`: read `text` once the input has ended
+    M4wrap,
+    /// `traceon(names...)`: report calls of the named macros, or of every
+    /// macro without names
+    Traceon,
+    /// `traceoff(names...)`: stop reporting calls of the named macros, or
+    /// of any macro without names
+    Traceoff,
+    /// `changeword(regex)`: change which text is taken as macro names
+    Changeword,
+    /// `__file__`: the name of the input the call was read from
+    File,
+    /// `__line__`: the line the call was read on
+    Line,
+    /// Predefined `__gnu__`, expands to nothing
+    Gnu,
+    /// Predefined `__unix__`, expands to nothing
+    Unix,
+}
+
+impl Builtin {
+    /// Every builtin, in registration order
+    pub const ALL: &'static [Builtin] = &[
+        Builtin::Define,
+        Builtin::Ifelse,
+        Builtin::Ifdef,
+        Builtin::Dnl,
+        Builtin::Builtin,
+        Builtin::Exit,
+        Builtin::Include,
+        Builtin::Sinclude,
+        Builtin::Paste,
+        Builtin::Spaste,
+        Builtin::Eval,
+        Builtin::Expr,
+        Builtin::M4wrap,
+        Builtin::Traceon,
+        Builtin::Traceoff,
+        Builtin::Changeword,
+        Builtin::File,
+        Builtin::Line,
+        Builtin::Gnu,
+        Builtin::Unix,
+    ];
+
+    /// Prefix applied to every builtin name in prefixed mode (GNU `-P`)
+    pub const PREFIX: &'static str = "m4_";
+
+    /// Unprefixed name of the builtin
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Define => "define",
+            Builtin::Ifelse => "ifelse",
+            Builtin::Ifdef => "ifdef",
+            Builtin::Dnl => "dnl",
+            Builtin::Builtin => "builtin",
+            Builtin::Exit => "m4exit",
+            Builtin::Include => "include",
+            Builtin::Sinclude => "sinclude",
+            Builtin::Paste => "paste",
+            Builtin::Spaste => "spaste",
+            Builtin::Eval => "eval",
+            Builtin::Expr => "expr",
+            Builtin::M4wrap => "m4wrap",
+            Builtin::Traceon => "traceon",
+            Builtin::Traceoff => "traceoff",
+            Builtin::Changeword => "changeword",
+            Builtin::File => "__file__",
+            Builtin::Line => "__line__",
+            Builtin::Gnu => "__gnu__",
+            Builtin::Unix => "__unix__",
+        }
+    }
+
+    /// Look up a builtin by its unprefixed name
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        Builtin::ALL.iter().copied().find(|b| b.name() == name)
+    }
+
+    /// What the builtin reaches outside the expander for, if anything
+    pub fn category(self) -> Option<BuiltinCategory> {
+        match self {
+            Builtin::Include | Builtin::Sinclude | Builtin::Paste | Builtin::Spaste => {
+                Some(BuiltinCategory::FileInclusion)
+            }
+            Builtin::Exit => Some(BuiltinCategory::Exit),
+            _ => None,
+        }
+    }
+
+    /// Whether GNU m4 only takes the name as a call when it is followed by
+    /// `(`, and as text otherwise
+    pub fn requires_arguments(self) -> bool {
+        matches!(
+            self,
+            Builtin::Define
+                | Builtin::Ifelse
+                | Builtin::Ifdef
+                | Builtin::Builtin
+                | Builtin::Include
+                | Builtin::Sinclude
+                | Builtin::Paste
+                | Builtin::Spaste
+                | Builtin::Eval
+                | Builtin::Expr
+                | Builtin::M4wrap
+                | Builtin::Changeword
+        )
+    }
+
+    /// Whether the builtin is a GNU extension, missing in traditional mode
+    pub fn is_gnu_extension(self) -> bool {
+        matches!(
+            self,
+            Builtin::Builtin | Builtin::Changeword | Builtin::File | Builtin::Line | Builtin::Gnu
+        )
+    }
+
+    /// Whether the builtin is defined in `dialect`
+    pub fn in_dialect(self, dialect: Dialect) -> bool {
+        match self {
+            Builtin::Paste | Builtin::Spaste | Builtin::Expr => dialect == Dialect::Bsd,
+            Builtin::Changeword | Builtin::Gnu => dialect != Dialect::Bsd,
+            // The classic dialect calls builtins even without parentheses,
+            // so these names stay text there, as they were in 0.1
+            Builtin::Eval
+            | Builtin::M4wrap
+            | Builtin::Traceon
+            | Builtin::Traceoff
+            | Builtin::File
+            | Builtin::Line => dialect != Dialect::Classic,
+            _ => true,
+        }
+    }
+
+    /// Whether the builtin exists on this platform
+    fn is_available(self) -> bool {
+        match self {
+            Builtin::Unix => cfg!(unix),
+            _ => true,
+        }
+    }
+}
+
+/// Which m4's rules for whitespace, arguments and builtin results the
+/// expander follows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// This crate's original rules: `ifelse` and `ifdef` compare and return
+    /// trimmed text, `define` trims the name and needs a body, builtins
+    /// are called even without parentheses, and `$#`, `$*` and `$@` are
+    /// plain text. Comments are dropped up to the end of their line, and a
+    /// `#` inside other text, as in `$#`, does not start one.
+    #[default]
+    Classic,
+    /// GNU m4's rules: arguments lose only their leading unquoted
+    /// whitespace and one level of quotes and are otherwise used as they
+    /// are, `ifelse` with a single argument is a comment, `define` with
+    /// one argument defines an empty macro, builtins that need arguments are
+    /// text without them, and macro bodies may use `$#`, `$*` and `$@`.
+    /// `eval`, `m4wrap`, `traceon`, `traceoff`, `__file__` and `__line__`
+    /// are only defined in this dialect and the BSD one.
+    Gnu,
+    /// The rules of the GNU dialect with BSD m4's differences: `paste`,
+    /// `spaste` and `expr` are defined and `__gnu__` is not, references to
+    /// arguments take one digit, so `$10` is `$1` followed by `0`, text
+    /// saved by `m4wrap` is read in the order it was saved rather than in
+    /// reverse, and `eval` takes radixes from 2 rather than from 1.
+    Bsd,
+}
+
+impl Dialect {
+    /// Every dialect
+    pub const ALL: &'static [Dialect] = &[Dialect::Classic, Dialect::Gnu, Dialect::Bsd];
+
+    /// Short name of the dialect, such as `gnu`
+    pub fn name(self) -> &'static str {
+        match self {
+            Dialect::Classic => "classic",
+            Dialect::Gnu => "gnu",
+            Dialect::Bsd => "bsd",
+        }
+    }
+
+    /// Look up a dialect by its short name
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        Dialect::ALL.iter().copied().find(|d| d.name() == name)
+    }
+}
+
+/// A builtin macro implemented in Rust, registered with
+/// [`Expander::define_native`]. Closures taking the same arguments as
+/// [`NativeBuiltin::call`] implement it.
+pub trait NativeBuiltin: Send + Sync {
+    /// Run a call with its collected arguments; a call without parentheses
+    /// has none
+    fn call(&self, context: &mut CallContext<'_>, args: &[String]) -> Result<(), M4Error>;
+}
+
+impl<F> NativeBuiltin for F
+where
+    F: Fn(&mut CallContext<'_>, &[String]) -> Result<(), M4Error> + Send + Sync,
+{
+    fn call(&self, context: &mut CallContext<'_>, args: &[String]) -> Result<(), M4Error> {
+        self(context, args)
+    }
+}
+
+/// How a [`NativeBuiltin`] is recognized and called
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NativeFlags {
+    /// The name is only a call when followed by `(`; on its own it is
+    /// output as text
+    pub requires_arguments: bool,
+    /// Arguments are collected as read, without expanding the macros in
+    /// them. Quotes are still removed.
+    pub raw_arguments: bool,
+}
+
+/// What a [`NativeBuiltin`] can see and do during a call
+pub struct CallContext<'a> {
+    expander: &'a mut Expander,
+    site: &'a CallSite,
+    /// Where text that is not rescanned goes
+    output: &'a mut String,
+    /// Text to rescan once the call returns
+    rescan: String,
+}
+
+impl CallContext<'_> {
+    /// Name the macro was called by
+    pub fn name(&self) -> &str {
+        &self.site.name
+    }
+
+    /// Where the call was read
+    pub fn location(&self) -> Location {
+        self.site.location
+    }
+
+    /// Output `text` as it is, without rescanning it
+    pub fn output(&mut self, text: &str) {
+        self.expander.destination(self.output).push_str(text);
+    }
+
+    /// Rescan `text` after the call, as the expansion of a macro is. Text
+    /// pushed back by several calls is read in the order it was pushed.
+    pub fn push_back(&mut self, text: &str) {
+        self.rescan.push_str(text);
+    }
+
+    /// The macros defined by the input
+    pub fn registry(&mut self) -> &mut MacroRegistry {
+        &mut self.expander.registry
+    }
+
+    /// Issue a warning about this call
+    pub fn warn(&mut self, kind: WarningKind, message: impl Into<String>) -> Result<(), M4Error> {
+        self.expander.warn(kind, message.into(), self.site)
+    }
+
+    /// An error about this call, to return from [`NativeBuiltin::call`]
+    pub fn error(&self, message: impl Into<String>) -> M4Error {
+        M4Error::InvalidArgument {
+            macro_name: self.site.name.clone(),
+            message: message.into(),
+            file: self.expander.input_file(),
+            location: self.expander.state.input.location(),
+            stack: self.expander.call_stack(Some(self.site)),
+        }
+    }
+}
+
+/// Answer of a [`MacroResolver`] for a name that is not defined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Not a macro; the word is output as text
+    Decline,
+    /// Expand this call with the body, asking again next time
+    Body(String),
+    /// Define the macro with the body, then expand the call
+    Define(String),
+}
+
+/// Supplies definitions for names the expander does not know, set with
+/// [`Expander::resolver`]. Closures taking the name implement it.
+pub trait MacroResolver: Send {
+    fn resolve(&mut self, name: &str) -> Resolution;
+}
+
+impl<F: FnMut(&str) -> Resolution + Send> MacroResolver for F {
+    fn resolve(&mut self, name: &str) -> Resolution {
+        self(name)
+    }
+}
+
+/// A registered [`NativeBuiltin`]
+#[derive(Clone)]
+struct Native {
+    builtin: Arc<dyn NativeBuiltin>,
+    flags: NativeFlags,
+}
+
+/// Bounds on the resources expanding one input may use, for input that is
+/// not trusted. `None` leaves a resource unbounded.
+///
+/// One input is the text passed to [`Expander::expand`] and its variants,
+/// the chunks passed to [`Expander::feed`] up to [`Expander::finish`], or
+/// everything an [`ExpandingReader`] reads. Exceeding the nesting depth fails
+/// with [`M4Error::NestingLimit`], any other limit with
+/// [`M4Error::LimitExceeded`] saying which.
+///
+/// There is no limit on the size of diversions because the expander has
+/// none: without `divert` and `undivert`, all text goes straight to the
+/// output and counts towards `output_bytes`. A diversion limit belongs
+/// here once diversions are added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpanderLimits {
+    /// Calls collecting their arguments at the same time
+    pub nesting_depth: Option<usize>,
+    /// Macro calls started, builtins included; text that merely looks like
+    /// a call of an undefined name does not count
+    pub invocations: Option<usize>,
+    /// Bytes of expanded output
+    pub output_bytes: Option<usize>,
+    /// Bytes collected into any single argument of a macro call
+    pub argument_bytes: Option<usize>,
+    /// Macros in the registry; redefining an existing one is always allowed
+    pub definitions: Option<usize>,
+}
+
+impl Default for ExpanderLimits {
+    /// A nesting depth of 100 and nothing else bounded
+    fn default() -> Self {
+        Self {
+            nesting_depth: Some(100),
+            invocations: None,
+            output_bytes: None,
+            argument_bytes: None,
+            definitions: None,
+        }
+    }
+}
+
+/// Stops expansion from another thread, for example when the client waiting
+/// for the output has gone away. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every expansion using this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
-/// M4 macro expander with recursive expansion
+/// M4 macro expander.
+///
+/// Input is scanned once: the expansion of each macro call is pushed back
+/// onto the input and rescanned before the text that followed the call.
+///
+/// Expansion runs on an explicit stack of calls whose arguments are still
+/// being collected rather than on the Rust call stack, so the only bound on
+/// recursion is the nesting limit. As in GNU m4, only those calls count
+/// towards it: a macro whose expansion ends in a call to itself (tail
+/// recursion) finishes before that call is read, and runs in constant depth.
 pub struct Expander {
     pub registry: MacroRegistry,
-    max_depth: usize,
+    /// Builtins keyed by the name they are recognized under
+    builtins: HashMap<String, Builtin>,
+    /// Builtins implemented outside the crate, keyed by name
+    natives: HashMap<String, Native>,
+    resolver: Option<Box<dyn MacroResolver>>,
+    prefix_builtins: bool,
+    /// GNU extensions are disabled (GNU `-G`)
+    traditional: bool,
+    dialect: Dialect,
+    /// Which text is taken as macro names, if not the usual names
+    words: Option<WordSyntax>,
+    limits: ExpanderLimits,
+    cancellation: Option<CancellationToken>,
+    deadline: Option<Instant>,
+    policy: SandboxPolicy,
+    /// Where `include` and `sinclude` read files from
+    files: Box<dyn FileProvider + Send>,
+    /// Input that spans of calls and definitions point into
+    file: FileId,
+    /// Inputs registered with [`Expander::add_source`] and files included
+    /// by the input
+    sources: SourceMap,
+    warning_sink: Option<Box<dyn WarningSink + Send>>,
+    disabled_warnings: HashSet<WarningKind>,
+    /// Warnings stop expansion with an error
+    fatal_warnings: bool,
+    trace_sink: Option<Box<dyn TraceSink + Send>>,
+    /// Macros whose calls are traced
+    traced: HashSet<String>,
+    /// Every call is traced, as after `traceon` without arguments
+    tracing_all: bool,
+    /// Text saved by `m4wrap` is kept when an input ends
+    hold_wrapped: bool,
+    /// Text saved by `m4wrap` in inputs that have ended, in the order it
+    /// was saved
+    held_wrapped: Vec<String>,
+    /// Progress through the input currently being expanded
+    state: State,
+}
+
+/// What a macro call runs once its arguments are known
+enum Target {
+    Builtin(Builtin),
+    Native(Native),
+    /// Body of a user-defined macro
+    Body(String),
+}
+
+/// A macro call whose arguments are being collected
+struct Frame {
+    site: CallSite,
+    target: Target,
+    /// Arguments so far; the last one is being collected
+    args: Vec<String>,
+    /// Unquoted parentheses open in the current argument
+    nesting: usize,
+    /// Whitespace before the current argument is still being skipped
+    skipping: bool,
+    /// Macros in the arguments are not expanded
+    raw: bool,
+    /// Where the call starts in the recorded input, if it was read from
+    /// the input rather than from an expansion
+    start: Option<usize>,
+}
+
+/// Where expansion stopped, so it can resume when more input arrives
+#[derive(Default)]
+struct State {
+    input: InputStack,
+    /// Calls collecting arguments, innermost last
+    frames: Vec<Frame>,
+    /// `dnl` is discarding the rest of its line
+    skipping_line: bool,
+    /// Text saved by `m4wrap`, in the order it was saved
+    wrapped: Vec<String>,
+    /// How to carry on after errors, if at all
+    recovery: Option<Recovery>,
+    /// Errors recovered from so far
+    errors: Vec<M4Error>,
+    /// Macro calls started so far
+    invocations: usize,
+    /// Bytes output by earlier runs
+    written: usize,
+}
+
+/// What a failed macro call is replaced with by
+/// [`Expander::expand_recovering`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    /// Output this text instead of the call
+    Placeholder(String),
+    /// Output the call as it was read from the input, quotes and all. A
+    /// call read from the expansion of another macro is output as its name
+    /// followed by its expanded arguments.
+    Original,
+}
+
+/// Result of [`Expander::expand_recovering`]
+#[derive(Debug)]
+pub struct Expansion {
+    /// Expanded text, with failed calls replaced
+    pub output: String,
+    /// Every error recovered from, in the order they occurred. Expansion
+    /// stopped at the last one if it was not caused by a macro call.
+    pub errors: Vec<M4Error>,
 }
 
 impl Expander {
     pub fn new(registry: MacroRegistry) -> Self {
         Self {
             registry,
-            max_depth: 100,
+            builtins: HashMap::new(),
+            natives: HashMap::new(),
+            resolver: None,
+            prefix_builtins: false,
+            traditional: false,
+            dialect: Dialect::default(),
+            words: None,
+            limits: ExpanderLimits::default(),
+            cancellation: None,
+            deadline: None,
+            policy: SandboxPolicy::default(),
+            files: Box::new(RealFs),
+            file: FileId::default(),
+            sources: SourceMap::new(),
+            warning_sink: None,
+            disabled_warnings: HashSet::new(),
+            fatal_warnings: false,
+            trace_sink: None,
+            traced: HashSet::new(),
+            tracing_all: false,
+            hold_wrapped: false,
+            held_wrapped: Vec::new(),
+            state: State::default(),
         }
+        .with_builtin_table()
+    }
+
+    /// Recognize builtins only under their `m4_`-prefixed names (GNU `-P`).
+    ///
+    /// With this enabled `define` is plain text and `m4_define` defines macros;
+    /// predefined macros are renamed the same way (`__gnu__` -> `m4___gnu__`).
+    pub fn prefix_builtins(mut self, enabled: bool) -> Self {
+        self.prefix_builtins = enabled;
+        self.with_builtin_table()
+    }
+
+    /// Disable GNU extensions, to check that input works with other
+    /// implementations of m4 (GNU `-G`).
+    ///
+    /// The `builtin` and `__gnu__` builtins are not defined, `__unix__` is
+    /// called `unix`, and `$10` in a macro body is `$1` followed by `0`.
+    pub fn traditional(mut self, enabled: bool) -> Self {
+        self.traditional = enabled;
+        self.with_builtin_table()
+    }
+
+    /// Whether GNU extensions are disabled
+    pub fn is_traditional(&self) -> bool {
+        self.traditional
+    }
+
+    /// Follow the rules of `dialect`
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self.with_builtin_table()
+    }
+
+    /// Take the text `words` matches as macro names, like GNU
+    /// `changeword`. See [`crate::word`].
+    pub fn word_syntax(mut self, words: WordSyntax) -> Self {
+        self.set_word_syntax(words);
+        self
+    }
+
+    fn set_word_syntax(&mut self, words: WordSyntax) {
+        // The usual names are read faster without the expression
+        self.words = (!words.is_default()).then_some(words);
+    }
+
+    /// Expression macro names are read with, if it is not the default
+    pub fn current_word_syntax(&self) -> Option<&WordSyntax> {
+        self.words.as_ref()
+    }
+
+    /// Rules the expander follows
+    pub fn current_dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// Limit how deeply macro expansions may nest; `0` removes the limit
+    pub fn nesting_limit(mut self, limit: usize) -> Self {
+        self.limits.nesting_depth = (limit != 0).then_some(limit);
+        self
+    }
+
+    /// Bound the resources expansion may use, replacing all current limits
+    pub fn limits(mut self, limits: ExpanderLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Stop expansion with [`M4Error::Cancelled`] once `token` is cancelled
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Stop expansion with [`M4Error::TimedOut`] once `deadline` has passed.
+    /// The deadline applies to every input the expander reads from then on.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Restrict the builtins input may call and the files they may read
+    pub fn sandbox(mut self, policy: SandboxPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Read the files input asks for from `files` instead of the disk
+    pub fn file_provider(mut self, files: impl FileProvider + Send + 'static) -> Self {
+        self.files = Box::new(files);
+        self
+    }
+
+    /// Ask `resolver` about every word that is not a defined name, before
+    /// outputting it as text
+    pub fn resolver(mut self, resolver: impl MacroResolver + 'static) -> Self {
+        self.resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Send warnings to `sink`. Without a sink they are discarded.
+    pub fn warning_sink(mut self, sink: impl WarningSink + Send + 'static) -> Self {
+        self.warning_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Turn warnings of one kind on or off; all are on by default
+    pub fn enable_warning(mut self, kind: WarningKind, enabled: bool) -> Self {
+        if enabled {
+            self.disabled_warnings.remove(&kind);
+        } else {
+            self.disabled_warnings.insert(kind);
+        }
+        self
+    }
+
+    /// Whether warnings of `kind` are issued
+    pub fn warning_enabled(&self, kind: WarningKind) -> bool {
+        !self.disabled_warnings.contains(&kind)
+    }
+
+    /// Stop expansion at the first enabled warning, failing with
+    /// [`M4Error::FatalWarning`] instead of passing it to the sink (GNU `-EE`)
+    pub fn fatal_warnings(mut self, enabled: bool) -> Self {
+        self.fatal_warnings = enabled;
+        self
+    }
+
+    /// Send the calls of traced macros to `sink`. Without a sink they are
+    /// discarded.
+    pub fn trace_sink(mut self, sink: impl TraceSink + Send + 'static) -> Self {
+        self.trace_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Start or stop tracing calls of `name`, as `traceon(name)` and
+    /// `traceoff(name)` do
+    pub fn trace(&mut self, name: &str, enabled: bool) {
+        if enabled {
+            self.traced.insert(name.to_owned());
+        } else {
+            self.traced.remove(name);
+        }
+    }
+
+    /// Whether calls of `name` are traced
+    pub fn is_traced(&self, name: &str) -> bool {
+        self.tracing_all || self.traced.contains(name)
+    }
+
+    /// Keep text saved by `m4wrap` when an input ends instead of reading it
+    /// then, so that several inputs act as one, like the files GNU m4 is
+    /// given. [`Expander::expand_wrapped_into`] reads it.
+    pub fn hold_wrapped(mut self, enabled: bool) -> Self {
+        self.hold_wrapped = enabled;
+        self
+    }
+
+    /// Identify the input passed to the following calls to `expand` or
+    /// `feed`, so spans of calls and definitions made by it point into it.
+    /// Files it includes are registered in [`Expander::sources`] with ids
+    /// of their own, so ids for the input should come from there too, by
+    /// way of [`Expander::add_source`].
+    pub fn set_file(&mut self, file: FileId) {
+        self.file = file;
+    }
+
+    /// Input currently being expanded, as set by [`Expander::set_file`]
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    /// Register input to be expanded in [`Expander::sources`], returning the
+    /// id to pass to [`Expander::set_file`]
+    pub fn add_source(&mut self, name: impl Into<String>, text: impl Into<String>) -> FileId {
+        self.sources.add(name, text)
+    }
+
+    /// Inputs registered with [`Expander::add_source`] and every file
+    /// included so far, for rendering diagnostics
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    /// Input the next character is read from: the innermost included file
+    /// being read, or else the input set with [`Expander::set_file`]
+    fn input_file(&self) -> FileId {
+        self.state.input.file().unwrap_or(self.file)
+    }
+
+    /// Whether builtins are recognized under their `m4_`-prefixed names
+    pub fn builtins_prefixed(&self) -> bool {
+        self.prefix_builtins
+    }
+
+    /// Name a builtin is recognized under by this expander
+    pub fn builtin_name(&self, builtin: Builtin) -> String {
+        Self::recognized_name(builtin, self.prefix_builtins, self.traditional)
+    }
+
+    fn recognized_name(builtin: Builtin, prefixed: bool, traditional: bool) -> String {
+        let prefix = if prefixed { Builtin::PREFIX } else { "" };
+        let name = match builtin {
+            Builtin::Unix if traditional => "unix",
+            _ => builtin.name(),
+        };
+        format!("{}{}", prefix, name)
+    }
+
+    /// Look up the builtin recognized under `name`, if any
+    pub fn builtin(&self, name: &str) -> Option<Builtin> {
+        self.builtins.get(name).copied()
+    }
+
+    /// Iterate over the names builtins are recognized under, in no particular order
+    pub fn builtins(&self) -> impl Iterator<Item = (&str, Builtin)> {
+        self.builtins.iter().map(|(name, &b)| (name.as_str(), b))
+    }
+
+    /// Recognize `builtin` under `name`, in addition to any existing names
+    pub fn define_builtin(&mut self, name: impl Into<String>, builtin: Builtin) {
+        self.builtins.insert(name.into(), builtin);
+    }
+
+    /// Recognize `name` as a call of `builtin`. It takes precedence over
+    /// builtins and macros of the same name, until the input defines or
+    /// undefines `name`.
+    pub fn define_native(
+        &mut self,
+        name: impl Into<String>,
+        flags: NativeFlags,
+        builtin: impl NativeBuiltin + 'static,
+    ) {
+        let builtin = Arc::new(builtin);
+        self.natives.insert(name.into(), Native { builtin, flags });
+    }
+
+    /// Check if `name` is a builtin or a user-defined macro
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.natives.contains_key(name)
+            || self.builtins.contains_key(name)
+            || self.registry.is_defined(name)
+    }
+
+    /// Remove a user definition or builtin so `name` is no longer recognized.
+    /// Returns whether anything was removed.
+    pub fn undefine(&mut self, name: &str) -> bool {
+        let native = self.natives.remove(name).is_some();
+        let builtin = self.builtins.remove(name).is_some();
+        self.registry.undefine(name).is_some() || builtin || native
+    }
+
+    /// Recognize the builtins of the current mode under their names,
+    /// replacing any builtins recognized before
+    fn with_builtin_table(mut self) -> Self {
+        let (prefixed, traditional) = (self.prefix_builtins, self.traditional);
+        let builtins = Builtin::ALL
+            .iter()
+            .filter(|&&b| self.provides(b))
+            .map(|&b| (Self::recognized_name(b, prefixed, traditional), b))
+            .collect();
+        self.builtins = builtins;
+        self
+    }
+
+    /// Whether `builtin` exists in the current mode, under whatever name
+    fn provides(&self, builtin: Builtin) -> bool {
+        builtin.is_available()
+            && builtin.in_dialect(self.dialect)
+            && !(self.traditional && builtin.is_gnu_extension())
     }
 
     pub fn into_registry(self) -> MacroRegistry {
@@ -64,293 +932,1140 @@ impl Expander {
     }
 
     /// Expand all macros in the input text (main entry point)
-    pub fn expand(&mut self, input: &str) -> Result<String, String> {
-        let tokens = M4Parser::parse_input(input).map_err(|e| e.to_string())?;
-        self.expand_tokens(&tokens)
+    pub fn expand(&mut self, input: &str) -> Result<String, M4Error> {
+        let mut output = String::new();
+        self.expand_into(input, &mut output)?;
+        Ok(output)
     }
 
-    /// Expand a list of tokens
-    pub fn expand_tokens(&mut self, tokens: &[Token]) -> Result<String, String> {
-        self.expand_tokens_with_depth(tokens, &[], 0)
+    /// Expand all macros in the input text, appending the result to `output`.
+    /// If expansion fails, `output` keeps the text expanded before the error.
+    pub fn expand_into(&mut self, input: &str, output: &mut String) -> Result<(), M4Error> {
+        self.expand_whole(input, None, output).0
     }
 
-    fn expand_tokens_with_depth(
+    /// Expand all macros in the input text, carrying on after errors.
+    ///
+    /// A builtin call that fails is replaced as `recovery` says and its
+    /// error is recorded. If the input ends inside a quoted string, comment
+    /// or argument list, the unread rest (or the placeholder) and the
+    /// unfinished calls are output as far as they were read. Exceeding the
+    /// nesting limit stops expansion, since the replacement would be
+    /// rescanned by the runaway recursion that caused it; so do the other
+    /// [`ExpanderLimits`] and `m4exit`. Each is recorded as the last error.
+    pub fn expand_recovering(&mut self, input: &str, recovery: Recovery) -> Expansion {
+        let mut output = String::new();
+        let (result, mut errors) = self.expand_whole(input, Some(recovery), &mut output);
+        errors.extend(result.err());
+        Expansion { output, errors }
+    }
+
+    /// Expand `input` on its own, returning how it ended and the errors
+    /// recovered from along the way
+    fn expand_whole(
         &mut self,
-        tokens: &[Token],
-        args: &[String],
-        depth: usize,
-    ) -> Result<String, String> {
-        if depth > self.max_depth {
-            return Err("Maximum expansion depth exceeded".to_string());
+        input: &str,
+        recovery: Option<Recovery>,
+        output: &mut String,
+    ) -> (Result<(), M4Error>, Vec<M4Error>) {
+        // Input passed to `feed` is set aside, not mixed with this one
+        let saved = std::mem::take(&mut self.state);
+        if recovery.is_some() {
+            self.state.input.record();
         }
+        self.state.recovery = recovery;
+        self.state.input.append(input);
+        self.state.input.finish();
+        let result = self.run(output);
+        let state = std::mem::replace(&mut self.state, saved);
+        (result, state.errors)
+    }
 
-        let mut result = String::new();
-        for token in tokens {
-            result.push_str(&self.expand_token(token, args, depth)?);
+    /// Expand the next chunk of an input that arrives in pieces, returning the
+    /// output that is final so far.
+    ///
+    /// Chunks may split names, quotes, comments and argument lists anywhere;
+    /// expansion stops where it needs more input and resumes with the next
+    /// chunk, so the output is the same as expanding the whole input at once.
+    /// Definitions made by earlier chunks stay in effect. Call
+    /// [`Expander::finish`] once the input has ended. After an error the rest
+    /// of the input is discarded.
+    pub fn feed(&mut self, chunk: &str) -> Result<String, M4Error> {
+        self.state.input.append(chunk);
+        let mut output = String::new();
+        match self.run(&mut output) {
+            Ok(()) => Ok(output),
+            Err(e) => {
+                self.state = State::default();
+                Err(e)
+            }
         }
-        Ok(result)
     }
 
-    /// Expand a single token
-    fn expand_token(
-        &mut self,
-        token: &Token,
-        args: &[String],
-        depth: usize,
-    ) -> Result<String, String> {
-        match token {
-            Token::MacroCall(call) => self.expand_macro_call(call, args, depth),
-            Token::Positional(n) => {
-                if *n > 0 && *n <= args.len() {
-                    Ok(args[*n - 1].clone())
-                } else {
-                    Ok(String::new())
+    /// End an input passed to [`Expander::feed`], expanding whatever it still
+    /// holds back. Fails if the input ends inside an unfinished construct.
+    pub fn finish(&mut self) -> Result<String, M4Error> {
+        self.state.input.finish();
+        let mut output = String::new();
+        let result = self.run(&mut output);
+        self.state = State::default();
+        result.map(|()| output)
+    }
+
+    /// Input passed to [`Expander::feed`] that has not been read yet. Text
+    /// already taken into the arguments of an unfinished call is not included.
+    pub fn pending(&self) -> &str {
+        self.state.input.unread()
+    }
+
+    /// Read the text saved by `m4wrap` while [`Expander::hold_wrapped`] was
+    /// enabled, as the end of an input, appending the result to `output`.
+    /// If expansion fails, `output` keeps the text expanded before the error.
+    pub fn expand_wrapped_into(&mut self, output: &mut String) -> Result<(), M4Error> {
+        let holding = std::mem::replace(&mut self.hold_wrapped, false);
+        let result = self.expand_into("", output);
+        self.hold_wrapped = holding;
+        result
+    }
+
+    /// Expand a list of tokens
+    pub fn expand_tokens(&mut self, tokens: &[Token]) -> Result<String, M4Error> {
+        let source: String = tokens.iter().map(ToString::to_string).collect();
+        self.expand(&source)
+    }
+
+    /// Read tokens until the input is exhausted or more of it is needed,
+    /// appending expanded text to `output` as soon as it is final
+    fn run(&mut self, output: &mut String) -> Result<(), M4Error> {
+        self.state
+            .input
+            .drop_comments(self.dialect == Dialect::Classic);
+        let start = output.len();
+        let result = self.read_tokens(output, start);
+        self.state.written += output.len() - start;
+        result
+    }
+
+    /// The loop of [`Expander::run`], which started with `start` bytes in
+    /// `output`
+    fn read_tokens(&mut self, output: &mut String, start: usize) -> Result<(), M4Error> {
+        loop {
+            if self.state.skipping_line {
+                if !self.state.input.skip_line() {
+                    return Ok(());
                 }
+                self.state.skipping_line = false;
             }
-            Token::Literal(s) => Ok(s.to_string()),
-            Token::Group(g) => {
-                // For quoted strings (lexeme starts with `), strip quotes and return content
-                // This implements M4's quote-to-delay-expansion behavior
-                let lexeme = g.lexeme.as_ref();
-                if lexeme.starts_with('`') && lexeme.ends_with('\'') {
-                    // Return the content without quotes (don't expand inner tokens)
-                    Ok(lexeme[1..lexeme.len() - 1].to_string())
-                } else {
-                    // For unquoted groups (like multi-token arguments), expand inner tokens
-                    self.expand_tokens_with_depth(&g.tokens, args, depth)
+            if let Some(frame) = self.state.frames.last_mut()
+                && frame.skipping
+            {
+                if !self.state.input.skip_whitespace() {
+                    return Ok(());
+                }
+                frame.skipping = false;
+            }
+
+            let file = self.input_file();
+            let location = self.state.input.location();
+            // Recorded input is only needed for calls still being read
+            if self.state.frames.is_empty() {
+                self.state.input.forget_recorded();
+            }
+            let recorded_at = self
+                .state
+                .input
+                .reading_stream()
+                .then(|| self.state.input.recorded().len());
+            let next = match self.state.input.next_token(self.words.as_mut()) {
+                Ok(next) => next,
+                Err(construct) => {
+                    let error = self.unexpected_eof(construct, file, location);
+                    return self.recover_at_end(error, output);
+                }
+            };
+            match next {
+                Lexed::Token(lexeme) => {
+                    self.expand_lexeme(lexeme, file, location, recorded_at, output)?;
+                    self.check_size(output.len() - start)?;
+                    self.check_interrupted(output.len() - start)?;
                 }
+                Lexed::Blocked => return Ok(()),
+                Lexed::End => match self.state.frames.last() {
+                    None if self.hold_wrapped => {
+                        self.held_wrapped.append(&mut self.state.wrapped);
+                        return Ok(());
+                    }
+                    None => {
+                        let mut wrapped = std::mem::take(&mut self.held_wrapped);
+                        wrapped.append(&mut self.state.wrapped);
+                        if wrapped.is_empty() {
+                            return Ok(());
+                        }
+                        // GNU m4 reads the text saved last first; BSD m4
+                        // reads it in order, as POSIX asks
+                        if self.dialect != Dialect::Bsd {
+                            wrapped.reverse();
+                        }
+                        self.state.input.push(wrapped.concat());
+                    }
+                    Some(frame) => {
+                        let (file, opened) = (frame.site.file, frame.site.location);
+                        let error = self.unexpected_eof(Unclosed::ArgumentList, file, opened);
+                        return self.recover_at_end(error, output);
+                    }
+                },
             }
         }
     }
 
-    /// Expand a macro call - core recursive logic
-    fn expand_macro_call(
+    /// Expand a single token, appending the text it stands for to the
+    /// argument being collected, or to `output` at top level. Macro calls push
+    /// their expansion back onto the input instead, so it is rescanned.
+    /// The token was read at `location` in `file`; `start` is where it
+    /// begins in the recorded input, if it was read from the input.
+    fn expand_lexeme(
         &mut self,
-        call: &MacroCall,
-        parent_args: &[String],
-        depth: usize,
-    ) -> Result<String, String> {
-        let name = call.name.as_ref();
-
-        // Handle builtin macros by name
-        match name {
-            "define" => {
-                // define(name, body) - extract and store in registry
-                if call.args.len() >= 2 {
-                    // Expand the name (to resolve ifdef, ifelse, etc.)
-                    let macro_name = self.expand_token(&call.args[0], parent_args, depth)?;
-                    let macro_name = macro_name.trim().to_string();
-                    // Store raw body tokens
-                    let body = self.extract_body_tokens(&call.args[1]);
-                    self.registry.define(macro_name, body);
+        lexeme: Lexeme,
+        file: FileId,
+        location: Location,
+        start: Option<usize>,
+        output: &mut String,
+    ) -> Result<(), M4Error> {
+        if let Some(frame) = self.state.frames.last_mut() {
+            match lexeme {
+                Lexeme::Comma if frame.nesting == 0 => {
+                    frame.args.push(String::new());
+                    frame.skipping = true;
+                    return Ok(());
                 }
-                Ok(String::new())
-            }
-            "ifelse" => self.expand_ifelse(&call.args, parent_args, depth),
-            "ifdef" => self.expand_ifdef(&call.args, parent_args, depth),
-            "dnl" => {
-                // Discard rest of line (handled in expand)
-                Ok(String::new())
-            }
-            _ => {
-                // User-defined macro: recursively expand each argument
-                let expanded_args = self.expand_arguments(&call.args, parent_args, depth)?;
-
-                // Look up in registry
-                if let Some(body) = self.registry.get(name) {
-                    let body = body.clone();
-                    // Expand the body with the expanded arguments
-                    let expanded =
-                        self.expand_tokens_with_depth(&body, &expanded_args, depth + 1)?;
-                    // Rescan: parse and expand the result
-                    self.rescan(&expanded, depth + 1)
-                } else {
-                    // Unknown macro - output as-is
-                    if expanded_args.is_empty() {
-                        Ok(name.to_owned())
-                    } else {
-                        Ok(format!("{}({})", name, expanded_args.join(", ")))
-                    }
+                Lexeme::Close if frame.nesting == 0 => {
+                    let frame = self.state.frames.pop().expect("frame is on the stack");
+                    return self.call(frame.site, frame.target, frame.args, frame.start, output);
                 }
+                Lexeme::Open => frame.nesting += 1,
+                Lexeme::Close => frame.nesting -= 1,
+                _ => {}
             }
         }
+
+        let raw = self.state.frames.last().is_some_and(|frame| frame.raw);
+        match lexeme {
+            Lexeme::Word(name) if raw => self.destination(output).push_str(&name),
+            Lexeme::Word(name) => return self.expand_word(name, file, location, start, output),
+            // Quoting delays expansion: the content is used without rescanning
+            Lexeme::Quoted(text) | Lexeme::Comment(text) | Lexeme::Text(text) => {
+                self.destination(output).push_str(&text)
+            }
+            Lexeme::Open => self.destination(output).push('('),
+            Lexeme::Comma => self.destination(output).push(','),
+            Lexeme::Close => self.destination(output).push(')'),
+        }
+        Ok(())
     }
 
-    /// Recursively expand macro call arguments
-    ///
-    /// For each argument:
-    /// - If it's a MacroCall for a defined macro, descend into that macro's definition,
-    ///   expand it fully, and use the result as the argument value
-    /// - If it's a Group (quoted), strip quotes and use literal content
-    /// - Otherwise, expand normally
-    fn expand_arguments(
+    /// Where expanded text goes: the argument being collected, if any
+    fn destination<'a>(&'a mut self, output: &'a mut String) -> &'a mut String {
+        match self.state.frames.last_mut() {
+            Some(frame) => frame
+                .args
+                .last_mut()
+                .expect("a frame has a current argument"),
+            None => output,
+        }
+    }
+
+    /// Expand a word: call the macro it names, or output it as-is. A call
+    /// with arguments starts collecting them; it runs once they are complete.
+    fn expand_word(
         &mut self,
-        args: &[Token],
-        parent_args: &[String],
-        depth: usize,
-    ) -> Result<Vec<String>, String> {
-        let mut result = Vec::with_capacity(args.len());
-        for arg in args {
-            result.push(self.expand_argument(arg, parent_args, depth)?);
+        word: String,
+        file: FileId,
+        location: Location,
+        start: Option<usize>,
+        output: &mut String,
+    ) -> Result<(), M4Error> {
+        // The lexer only ends a word once the character after it is known
+        let has_arguments = self.state.input.peek() == Some('(');
+        let name = match &self.words {
+            Some(words) => words.macro_name(&word).to_owned(),
+            None => word.clone(),
+        };
+        let target = if let Some(native) = self.natives.get(&name) {
+            let text = native.flags.requires_arguments && !has_arguments;
+            (!text).then(|| Target::Native(native.clone()))
+        } else if let Some(builtin) = self.builtin(&name) {
+            let text =
+                self.dialect != Dialect::Classic && builtin.requires_arguments() && !has_arguments;
+            (!text).then_some(Target::Builtin(builtin))
+        } else if let Some(body) = self.registry.get(&name) {
+            Some(Target::Body(body.to_owned()))
+        } else {
+            self.resolve(&name, file, location)?
+        };
+        let Some(target) = target else {
+            // Not a macro: the word is text, and so is whatever follows it,
+            // parentheses included
+            self.destination(output).push_str(&word);
+            return Ok(());
+        };
+
+        let site = CallSite {
+            definition: self.registry.definition_span(&name),
+            name,
+            file,
+            location,
+            arguments: Vec::new(),
+        };
+        self.check_call(&site)?;
+
+        if has_arguments {
+            self.state.input.next_char();
+            let raw = matches!(&target, Target::Native(native) if native.flags.raw_arguments);
+            self.state.frames.push(Frame {
+                site,
+                target,
+                args: vec![String::new()],
+                nesting: 0,
+                skipping: true,
+                raw,
+                start,
+            });
+            Ok(())
+        } else {
+            self.call(site, target, Vec::new(), start, output)
+        }
+    }
+
+    /// How macro bodies refer to the arguments of a call
+    fn body_rules(&self) -> BodyRules {
+        BodyRules {
+            special: self.dialect != Dialect::Classic,
+            single_digit: self.traditional || self.dialect == Dialect::Bsd,
         }
-        Ok(result)
     }
 
-    /// Expand a single argument, recursively descending into macro definitions
-    fn expand_argument(
+    /// Ask the resolver, if any, what the undefined `name` read at
+    /// `location` in `file` stands for
+    fn resolve(
         &mut self,
-        arg: &Token,
-        parent_args: &[String],
-        depth: usize,
-    ) -> Result<String, String> {
-        match arg {
-            Token::MacroCall(call) => {
-                // Check if this is a defined macro - if so, descend into its definition
-                let name = call.name.as_ref();
-
-                // Handle built-in macros normally
-                if matches!(name, "define" | "ifelse" | "ifdef" | "dnl") {
-                    return self.expand_macro_call(call, parent_args, depth);
+        name: &str,
+        file: FileId,
+        location: Location,
+    ) -> Result<Option<Target>, M4Error> {
+        let Some(resolver) = &mut self.resolver else {
+            return Ok(None);
+        };
+        match resolver.resolve(name) {
+            Resolution::Decline => Ok(None),
+            Resolution::Body(body) => Ok(Some(Target::Body(body))),
+            Resolution::Define(body) => {
+                if let Some(max) = self.limits.definitions
+                    && self.registry.len() >= max
+                {
+                    let site = CallSite {
+                        name: name.to_owned(),
+                        file,
+                        location,
+                        definition: None,
+                        arguments: Vec::new(),
+                    };
+                    return Err(self.limit_exceeded(Limit::Definitions, max, Some(&site)));
                 }
+                self.registry.define_source(name.to_owned(), &body);
+                Ok(Some(Target::Body(body)))
+            }
+        }
+    }
+
+    /// Count the call at `site`, failing if starting it would exceed the
+    /// nesting or invocation limit
+    fn check_call(&mut self, site: &CallSite) -> Result<(), M4Error> {
+        let depth = self.state.frames.len() + 1;
+        if let Some(limit) = self.limits.nesting_depth
+            && depth > limit
+        {
+            return Err(M4Error::NestingLimit {
+                limit,
+                file: self.input_file(),
+                location: self.state.input.location(),
+                stack: self.call_stack(Some(site)),
+            });
+        }
+        self.state.invocations += 1;
+        if let Some(max) = self.limits.invocations
+            && self.state.invocations > max
+        {
+            return Err(self.limit_exceeded(Limit::Invocations, max, Some(site)));
+        }
+        Ok(())
+    }
+
+    /// Fail if the output, of which this run produced `written` bytes, or
+    /// the argument being collected has grown past its limit
+    fn check_size(&self, written: usize) -> Result<(), M4Error> {
+        if let Some(max) = self.limits.output_bytes
+            && self.state.written + written > max
+        {
+            return Err(self.limit_exceeded(Limit::OutputBytes, max, None));
+        }
+        if let Some(max) = self.limits.argument_bytes
+            && let Some(frame) = self.state.frames.last()
+            && frame.args.last().map_or(0, String::len) > max
+        {
+            return Err(self.limit_exceeded(Limit::ArgumentBytes, max, None));
+        }
+        Ok(())
+    }
+
+    /// Fail if expansion was cancelled or its deadline has passed. Checked
+    /// after every token, so at least at every macro call.
+    fn check_interrupted(&self, written: usize) -> Result<(), M4Error> {
+        let cancelled = self.cancellation.as_ref().is_some_and(|t| t.is_cancelled());
+        let timed_out = self.deadline.is_some_and(|d| Instant::now() >= d);
+        if !cancelled && !timed_out {
+            return Ok(());
+        }
+        let progress = Progress {
+            invocations: self.state.invocations,
+            output_bytes: self.state.written + written,
+        };
+        let file = self.input_file();
+        let location = self.state.input.location();
+        let stack = self.call_stack(None);
+        Err(if cancelled {
+            M4Error::Cancelled {
+                progress,
+                file,
+                location,
+                stack,
+            }
+        } else {
+            M4Error::TimedOut {
+                progress,
+                file,
+                location,
+                stack,
+            }
+        })
+    }
+
+    fn limit_exceeded(&self, limit: Limit, max: usize, current: Option<&CallSite>) -> M4Error {
+        M4Error::LimitExceeded {
+            limit,
+            max,
+            file: self.input_file(),
+            location: self.state.input.location(),
+            stack: self.call_stack(current),
+        }
+    }
+
+    /// Calls collecting arguments, outermost first, followed by `current`
+    fn call_stack(&self, current: Option<&CallSite>) -> Vec<CallSite> {
+        self.state
+            .frames
+            .iter()
+            .map(|frame| CallSite {
+                arguments: CallSite::preview(&frame.args),
+                ..frame.site.clone()
+            })
+            .chain(current.cloned())
+            .collect()
+    }
+
+    /// Issue a warning about the builtin call at `site`
+    fn warn(&mut self, kind: WarningKind, message: String, site: &CallSite) -> Result<(), M4Error> {
+        if !self.warning_enabled(kind) {
+            return Ok(());
+        }
+        let warning = Warning {
+            kind,
+            message,
+            file: site.file,
+            location: site.location,
+            stack: self.call_stack(Some(site)),
+        };
+        if self.fatal_warnings {
+            return Err(M4Error::FatalWarning { warning });
+        }
+        if let Some(sink) = &mut self.warning_sink {
+            sink.warn(warning);
+        }
+        Ok(())
+    }
 
-                if self.registry.is_defined(name) {
-                    // Recursively expand this macro call
-                    self.expand_macro_call(call, parent_args, depth)
+    /// Warn if a call to `builtin` got fewer than `min` or more than `max`
+    /// arguments
+    fn check_arguments(
+        &mut self,
+        builtin: Builtin,
+        site: &CallSite,
+        args: &[String],
+        min: usize,
+        max: usize,
+    ) -> Result<(), M4Error> {
+        // GNU m4 takes a bare name without arguments as text rather than as
+        // a call, so it has nothing to warn about
+        if args.is_empty() && min > 0 {
+            return Ok(());
+        }
+        let name = self.builtin_name(builtin);
+        if args.len() < min {
+            let message = format!("too few arguments to builtin `{}'", name);
+            self.warn(WarningKind::TooFewArguments, message, site)?;
+        } else if args.len() > max {
+            let message = format!("excess arguments to builtin `{}' ignored", name);
+            self.warn(WarningKind::ExcessArguments, message, site)?;
+        }
+        Ok(())
+    }
+
+    /// The input ended inside `construct`, opened at `opened` in `file`
+    fn unexpected_eof(&self, construct: Unclosed, file: FileId, opened: Location) -> M4Error {
+        M4Error::UnexpectedEof {
+            construct,
+            opened,
+            file,
+            location: self.state.input.end_location(),
+            stack: self.call_stack(None),
+        }
+    }
+
+    /// Run a macro call whose arguments are complete
+    fn call(
+        &mut self,
+        mut site: CallSite,
+        target: Target,
+        args: Vec<String>,
+        start: Option<usize>,
+        output: &mut String,
+    ) -> Result<(), M4Error> {
+        site.arguments = CallSite::preview(&args);
+        // Decided before the call, as GNU m4 does, so `traceoff` is traced
+        // and `traceon` is not
+        let traced = self.is_traced(&site.name);
+        let result = match target {
+            Target::Builtin(builtin) => self.call_builtin(builtin, &site, &args, output),
+            Target::Native(native) => {
+                let mut context = CallContext {
+                    expander: self,
+                    site: &site,
+                    output,
+                    rescan: String::new(),
+                };
+                native
+                    .builtin
+                    .call(&mut context, &args)
+                    .map(|()| context.rescan)
+            }
+            Target::Body(body) => {
+                let expansion = substitute(&body, &site.name, &args, self.body_rules());
+                if self.expands_to_itself(&site.name, &expansion) {
+                    Err(M4Error::SelfReference {
+                        name: site.name.clone(),
+                        file: self.input_file(),
+                        location: self.state.input.location(),
+                        stack: self.call_stack(Some(&site)),
+                    })
                 } else {
-                    // Not a defined macro - expand as normal token
-                    self.expand_token(arg, parent_args, depth)
+                    Ok(expansion)
                 }
             }
-            Token::Group(g) => {
-                // Quoted string - strip quotes and return content (no expansion)
-                let lexeme = g.lexeme.as_ref();
-                if lexeme.starts_with('`') && lexeme.ends_with('\'') {
-                    Ok(lexeme[1..lexeme.len() - 1].to_string())
-                } else {
-                    // Unquoted group - expand inner tokens
-                    self.expand_tokens_with_depth(&g.tokens, parent_args, depth)
+        };
+        let error = match result {
+            Ok(expansion) => {
+                if traced {
+                    self.trace_call(&site, &args, &expansion);
                 }
+                // Rescan: the expansion is read again before the rest of the input
+                self.state.input.push(expansion);
+                return Ok(());
             }
-            _ => self.expand_token(arg, parent_args, depth),
+            Err(error) => error,
+        };
+
+        // `m4exit` is a request to stop, not a failure to recover from, and
+        // input that runs into a limit is not worth expanding further
+        let stops = matches!(error, M4Error::Abort { .. } | M4Error::LimitExceeded { .. });
+        let recovery = match &self.state.recovery {
+            Some(recovery) if !stops => recovery,
+            _ => return Err(error),
+        };
+        let text = match (recovery, start) {
+            (Recovery::Placeholder(text), _) => text.clone(),
+            (Recovery::Original, Some(start)) => self.state.input.recorded()[start..].to_owned(),
+            (Recovery::Original, None) => call_text(&site.name, &args),
+        };
+        self.state.errors.push(error);
+        self.destination(output).push_str(&text);
+        Ok(())
+    }
+
+    /// Report the traced call at `site`, which expanded to `expansion`
+    fn trace_call(&mut self, site: &CallSite, args: &[String], expansion: &str) {
+        if let Some(sink) = &mut self.trace_sink {
+            sink.trace(Trace {
+                depth: self.state.frames.len() + 1,
+                name: site.name.clone(),
+                arguments: args.to_vec(),
+                expansion: expansion.to_owned(),
+                file: site.file,
+                location: site.location,
+            });
         }
     }
 
-    /// Extract text content from a token (for getting macro names, comparison values, etc.)
-    fn extract_text(&self, token: &Token, parent_args: &[String]) -> Result<String, String> {
-        match token {
-            Token::Literal(s) => Ok(s.to_string()),
-            Token::Positional(n) => {
-                if *n > 0 && *n <= parent_args.len() {
-                    Ok(parent_args[*n - 1].clone())
-                } else {
-                    Ok(String::new())
+    /// Handle an error that ends expansion early. When recovering, the
+    /// error is recorded, the unread input (or the placeholder) is output,
+    /// and unfinished calls are output as far as they were read.
+    fn recover_at_end(&mut self, error: M4Error, output: &mut String) -> Result<(), M4Error> {
+        let Some(recovery) = self.state.recovery.clone() else {
+            return Err(error);
+        };
+        self.state.errors.push(error);
+
+        let start = self.state.frames.first().map(|frame| frame.start);
+        let rest = self.state.input.take_rest();
+        if let (Recovery::Original, Some(Some(start))) = (&recovery, start) {
+            // The unfinished calls and the rest, as they were read
+            let text = self.state.input.recorded()[start..].to_owned();
+            self.state.frames.clear();
+            output.push_str(&text);
+            return Ok(());
+        }
+        let text = match &recovery {
+            Recovery::Placeholder(text) if !rest.is_empty() => text.clone(),
+            Recovery::Placeholder(_) => String::new(),
+            Recovery::Original => rest,
+        };
+        self.destination(output).push_str(&text);
+        while let Some(frame) = self.state.frames.pop() {
+            let text = match &recovery {
+                Recovery::Placeholder(text) => text.clone(),
+                Recovery::Original => format!("{}({}", frame.site.name, frame.args.join(", ")),
+            };
+            self.destination(output).push_str(&text);
+        }
+        Ok(())
+    }
+
+    /// Whether rescanning `expansion`, the expansion of the macro `name`,
+    /// leads straight back to calling `name` without arguments, directly or
+    /// through other macros called without arguments. Nothing runs in
+    /// between that could change a definition, so that would repeat forever.
+    /// A word at the end of the expansion is followed by the rest of the
+    /// input, which may give it arguments.
+    fn expands_to_itself(&mut self, name: &str, expansion: &str) -> bool {
+        // Longer chains are not worth following on every call
+        const MAX_STEPS: usize = 8;
+
+        // Only a word at the very start matters, so the expansion itself is
+        // never copied; just the bodies along the chain are substituted
+        let mut text = Cow::Borrowed(expansion);
+        // What follows `text` before the rest of the input
+        let mut after = String::new();
+        for _ in 0..MAX_STEPS {
+            let end = match &mut self.words {
+                Some(words) => words.word_len(&text),
+                None if text.starts_with(is_word_start) => {
+                    Some(text.find(|c| !is_word_char(c)).unwrap_or(text.len()))
+                }
+                None => None,
+            };
+            let Some(end) = end else {
+                return false;
+            };
+            let next = match text[end..].chars().next().or_else(|| after.chars().next()) {
+                Some(c) => Some(c),
+                None => match self.state.input.peek() {
+                    // More input could still bring arguments
+                    None if !self.state.input.is_finished() => return false,
+                    next => next,
+                },
+            };
+            if let Some(c) = next {
+                let longer = match &mut self.words {
+                    Some(words) => words.word_len(&format!("{}{}", &text[..end], c)) != Some(end),
+                    None => is_word_char(c),
+                };
+                if c == '(' || longer {
+                    return false;
+                }
+            }
+            let word = match &self.words {
+                Some(words) => words.macro_name(&text[..end]),
+                None => &text[..end],
+            };
+            if self.natives.contains_key(word) || self.builtin(word).is_some() {
+                return false;
+            }
+            if word == name {
+                return true;
+            }
+            let Some(body) = self.registry.get(word) else {
+                return false;
+            };
+            after.insert_str(0, &text[end..]);
+            text = Cow::Owned(substitute(body, word, &[], self.body_rules()));
+        }
+        false
+    }
+
+    /// Run a builtin on collected arguments, returning the text to rescan
+    fn call_builtin(
+        &mut self,
+        builtin: Builtin,
+        site: &CallSite,
+        args: &[String],
+        output: &mut String,
+    ) -> Result<String, M4Error> {
+        match builtin {
+            Builtin::Define => {
+                // define(name, body) - store the body for expansion on use
+                let gnu = self.dialect != Dialect::Classic;
+                let min = if gnu { 1 } else { 2 };
+                self.check_arguments(builtin, site, args, min, 2)?;
+                if args.len() >= min {
+                    let name = if gnu {
+                        args[0].clone()
+                    } else {
+                        args[0].trim().to_string()
+                    };
+                    if let Some(max) = self.limits.definitions
+                        && self.registry.len() >= max
+                        && !self.registry.is_defined(&name)
+                    {
+                        return Err(self.limit_exceeded(Limit::Definitions, max, Some(site)));
+                    }
+                    let span = Span {
+                        file: site.file,
+                        start: site.location,
+                        end: self.state.input.location(),
+                    };
+                    let body = args.get(1).map_or("", String::as_str);
+                    // The definition replaces a builtin or native of the same
+                    // name
+                    self.builtins.remove(&name);
+                    self.natives.remove(&name);
+                    self.registry.define_source_at(name, body, span);
                 }
+                Ok(String::new())
             }
-            Token::Group(g) => {
-                // For groups, concatenate all inner text
-                let mut result = String::new();
-                for t in &g.tokens {
-                    result.push_str(&self.extract_text(t, parent_args)?);
+            Builtin::Ifelse => {
+                // GNU m4 takes a single argument as a comment, so it does
+                // not warn about it
+                if args.len() != 1 {
+                    self.check_arguments(builtin, site, args, 3, usize::MAX)?;
                 }
-                Ok(result)
+                Ok(self.expand_ifelse(args))
+            }
+            Builtin::Ifdef => {
+                self.check_arguments(builtin, site, args, 2, 3)?;
+                Ok(self.expand_ifdef(args))
+            }
+            Builtin::Dnl => {
+                self.check_arguments(builtin, site, args, 0, 0)?;
+                // Discard the rest of the line, including the newline
+                self.state.skipping_line = true;
+                Ok(String::new())
             }
-            Token::MacroCall(call) => {
-                // For a simple identifier (no args), just return the name
-                if call.args.is_empty() {
-                    Ok(call.name.to_string())
+            Builtin::Builtin => {
+                // builtin(name, args...) - call by unprefixed name
+                let Some((name, rest)) = args.split_first() else {
+                    return Ok(String::new());
+                };
+                let builtin = Builtin::from_name(name)
+                    .filter(|&b| self.provides(b))
+                    .ok_or_else(|| M4Error::UnknownBuiltin {
+                        name: name.clone(),
+                        file: self.input_file(),
+                        location: self.state.input.location(),
+                        stack: self.call_stack(Some(site)),
+                    })?;
+                self.call_builtin(builtin, site, rest, output)
+            }
+            Builtin::Exit => {
+                self.check_allowed(builtin, site)?;
+                self.check_arguments(builtin, site, args, 0, 1)?;
+                let arg = args.first().map_or("", |arg| arg.trim());
+                let status = if arg.is_empty() {
+                    0
                 } else {
-                    // Has args, treat as complex expression
-                    Ok(String::new())
+                    match arg.parse::<i64>() {
+                        Ok(n) => u8::try_from(n).map_err(|_| M4Error::InvalidArgument {
+                            macro_name: site.name.clone(),
+                            message: format!("exit status {} out of range", n),
+                            file: self.input_file(),
+                            location: self.state.input.location(),
+                            stack: self.call_stack(Some(site)),
+                        })?,
+                        // As in GNU m4, a status that is not a number is a
+                        // failure
+                        Err(_) => {
+                            let message = format!(
+                                "non-numeric argument to builtin `{}'",
+                                self.builtin_name(builtin)
+                            );
+                            self.warn(WarningKind::NonNumeric, message, site)?;
+                            1
+                        }
+                    }
+                };
+                Err(M4Error::Abort {
+                    status,
+                    file: self.input_file(),
+                    location: self.state.input.location(),
+                    stack: self.call_stack(Some(site)),
+                })
+            }
+            Builtin::Include | Builtin::Sinclude => {
+                let text = self.read_file(builtin, site, args, builtin == Builtin::Sinclude)?;
+                // Read next, but with positions in the included file
+                if !text.is_empty() {
+                    let name = &args[0];
+                    let file = match self.sources.find(name, &text) {
+                        Some(file) => file,
+                        None => self.sources.add(name.as_str(), text.as_str()),
+                    };
+                    self.state.input.push_file(text, file);
+                }
+                Ok(String::new())
+            }
+            Builtin::Paste | Builtin::Spaste => {
+                let text = self.read_file(builtin, site, args, builtin == Builtin::Spaste)?;
+                self.destination(output).push_str(&text);
+                Ok(String::new())
+            }
+            Builtin::Eval | Builtin::Expr => {
+                self.check_arguments(builtin, site, args, 1, 3)?;
+                self.eval(builtin, site, args)
+            }
+            Builtin::M4wrap => {
+                self.check_arguments(builtin, site, args, 1, usize::MAX)?;
+                if !args.is_empty() {
+                    // GNU m4 joins several arguments with spaces
+                    self.state.wrapped.push(args.join(" "));
+                }
+                Ok(String::new())
+            }
+            Builtin::Traceon | Builtin::Traceoff => {
+                let enabled = builtin == Builtin::Traceon;
+                if args.is_empty() {
+                    self.tracing_all = enabled;
+                    if !enabled {
+                        self.traced.clear();
+                    }
+                }
+                for name in args {
+                    self.trace(name, enabled);
+                }
+                Ok(String::new())
+            }
+            Builtin::File => {
+                self.check_arguments(builtin, site, args, 0, 0)?;
+                let name = self.sources.name(site.file).unwrap_or("stdin");
+                // Quoted, so a name that is also a macro's stays text
+                Ok(format!("`{}'", name))
+            }
+            Builtin::Line => {
+                self.check_arguments(builtin, site, args, 0, 0)?;
+                Ok(site.location.line.to_string())
+            }
+            Builtin::Changeword => {
+                self.check_arguments(builtin, site, args, 1, 1)?;
+                let Some(pattern) = args.first() else {
+                    return Ok(String::new());
+                };
+                match WordSyntax::new(pattern) {
+                    Ok(words) => self.set_word_syntax(words),
+                    Err(e) => {
+                        return Err(M4Error::InvalidArgument {
+                            macro_name: site.name.clone(),
+                            message: e.to_string(),
+                            file: self.input_file(),
+                            location: self.state.input.location(),
+                            stack: self.call_stack(Some(site)),
+                        });
+                    }
                 }
+                Ok(String::new())
+            }
+            Builtin::Gnu | Builtin::Unix => Ok(String::new()),
+        }
+    }
+
+    /// Evaluate the expression a call to `eval` or `expr` was given, in the
+    /// radix and width it asks for
+    fn eval(
+        &mut self,
+        builtin: Builtin,
+        site: &CallSite,
+        args: &[String],
+    ) -> Result<String, M4Error> {
+        let Some(expression) = args.first() else {
+            return Ok(String::new());
+        };
+        let Some(radix) = self.numeric_argument(builtin, site, args.get(1), 10)? else {
+            return Ok(String::new());
+        };
+        let Some(width) = self.numeric_argument(builtin, site, args.get(2), 1)? else {
+            return Ok(String::new());
+        };
+        // BSD m4 has no radix 1, which GNU m4 writes a `1` per unit in
+        let min_radix = if self.dialect == Dialect::Bsd { 2 } else { 1 };
+        if !(min_radix..=36).contains(&radix) {
+            let message = format!(
+                "radix {} out of range (valid ranges are {} to 36)",
+                radix, min_radix
+            );
+            return Err(self.invalid_argument(site, message));
+        }
+        if width < 0 {
+            return Err(self.invalid_argument(site, format!("negative width {}", width)));
+        }
+
+        let value = if expression.trim().is_empty() {
+            let message = format!(
+                "empty string treated as 0 in builtin `{}'",
+                self.builtin_name(builtin)
+            );
+            self.warn(WarningKind::NonNumeric, message, site)?;
+            0
+        } else {
+            eval::evaluate(expression)
+                .map_err(|e| self.invalid_argument(site, format!("{} in `{}'", e, expression)))?
+        };
+        Ok(eval::format(value, radix as u32, width as usize))
+    }
+
+    /// The number `arg` of a call to `builtin` holds, or `default` if it
+    /// is missing or empty. Anything else is warned about and gives `None`.
+    fn numeric_argument(
+        &mut self,
+        builtin: Builtin,
+        site: &CallSite,
+        arg: Option<&String>,
+        default: i64,
+    ) -> Result<Option<i64>, M4Error> {
+        let text = arg.map_or("", |arg| arg.trim());
+        if text.is_empty() {
+            return Ok(Some(default));
+        }
+        match text.parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => {
+                let message = format!(
+                    "non-numeric argument to builtin `{}'",
+                    self.builtin_name(builtin)
+                );
+                self.warn(WarningKind::NonNumeric, message, site)?;
+                Ok(None)
             }
         }
     }
 
-    /// Extract body tokens from an argument, converting to owned for storage
-    fn extract_body_tokens(&self, token: &Token) -> Vec<Token<'static>> {
-        match token {
-            Token::Group(g) => g.tokens.iter().map(|t| t.clone().into_owned()).collect(),
-            _ => vec![token.clone().into_owned()],
+    /// Error about an argument of the call at `site`
+    fn invalid_argument(&self, site: &CallSite, message: String) -> M4Error {
+        M4Error::InvalidArgument {
+            macro_name: site.name.clone(),
+            message,
+            file: self.input_file(),
+            location: self.state.input.location(),
+            stack: self.call_stack(Some(site)),
         }
     }
 
-    fn expand_ifelse(
+    /// Read the file named by the argument of a call to `builtin`, which
+    /// reads nothing if `silent` and the file cannot be opened
+    fn read_file(
         &mut self,
-        args: &[Token],
-        parent_args: &[String],
-        depth: usize,
-    ) -> Result<String, String> {
+        builtin: Builtin,
+        site: &CallSite,
+        args: &[String],
+        silent: bool,
+    ) -> Result<String, M4Error> {
+        self.check_arguments(builtin, site, args, 1, 1)?;
+        let Some(name) = args.first() else {
+            return Ok(String::new());
+        };
+        self.check_allowed(builtin, site)?;
+        let error = match self.policy.resolve(name, self.files.as_ref()) {
+            Ok(path) => match self.files.read(&path) {
+                Ok(text) => return Ok(text),
+                Err(e) => e,
+            },
+            Err(Access::Io(e)) => e,
+            Err(Access::Outside) => {
+                return Err(M4Error::PolicyViolation {
+                    macro_name: site.name.clone(),
+                    message: format!("`{}' is outside the allowed directory", name),
+                    file: self.input_file(),
+                    location: self.state.input.location(),
+                    stack: self.call_stack(Some(site)),
+                });
+            }
+        };
+        if silent {
+            return Ok(String::new());
+        }
+        Err(M4Error::InvalidArgument {
+            macro_name: site.name.clone(),
+            message: format!("cannot open `{}': {}", name, error),
+            file: self.input_file(),
+            location: self.state.input.location(),
+            stack: self.call_stack(Some(site)),
+        })
+    }
+
+    /// Fail if the sandbox policy does not allow calling `builtin`
+    fn check_allowed(&self, builtin: Builtin, site: &CallSite) -> Result<(), M4Error> {
+        match builtin.category() {
+            Some(category) if !self.policy.allows(category) => Err(M4Error::PolicyViolation {
+                macro_name: site.name.clone(),
+                message: format!("{} is disabled", category),
+                file: self.input_file(),
+                location: self.state.input.location(),
+                stack: self.call_stack(Some(site)),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn expand_ifelse(&self, args: &[String]) -> String {
+        if self.dialect != Dialect::Classic {
+            return expand_gnu_ifelse(args).to_owned();
+        }
         // ifelse(a, b, then, d, e, then2, ..., else)
         // Process in groups of 3
         let mut i = 0;
         while i + 2 < args.len() {
-            let a = self.expand_token(&args[i], parent_args, depth)?;
-            let b = self.expand_token(&args[i + 1], parent_args, depth)?;
             // M4 trims whitespace for comparison
-            if a.trim() == b.trim() {
-                let result = self.expand_token(&args[i + 2], parent_args, depth)?;
-                return Ok(result.trim().to_string());
+            if args[i].trim() == args[i + 1].trim() {
+                return args[i + 2].trim().to_string();
             }
             i += 3;
         }
 
         // Remaining arg is the else clause
-        if i < args.len() {
-            let result = self.expand_token(&args[i], parent_args, depth)?;
-            Ok(result.trim().to_string())
-        } else {
-            Ok(String::new())
-        }
+        args.get(i)
+            .map(|arg| arg.trim().to_string())
+            .unwrap_or_default()
     }
 
-    fn expand_ifdef(
-        &mut self,
-        args: &[Token],
-        parent_args: &[String],
-        depth: usize,
-    ) -> Result<String, String> {
+    fn expand_ifdef(&self, args: &[String]) -> String {
         // ifdef(name, then, else?)
-        if args.is_empty() {
-            return Ok(String::new());
+        let Some(name) = args.first() else {
+            return String::new();
+        };
+
+        if self.dialect != Dialect::Classic {
+            let branch = if self.is_defined(name) { 1 } else { 2 };
+            return args.get(branch).cloned().unwrap_or_default();
         }
+        let branch = if self.is_defined(name.trim()) { 1 } else { 2 };
+        args.get(branch)
+            .map(|arg| arg.trim().to_string())
+            .unwrap_or_default()
+    }
+}
 
-        // Extract the macro name without expanding (for ifdef, we check the name, not its value)
-        let name = self.extract_text(&args[0], parent_args)?;
-        let name = name.trim();
+/// Which references [`substitute`] replaces in a macro body
+#[derive(Debug, Clone, Copy)]
+struct BodyRules {
+    /// `$#`, `$*` and `$@`
+    special: bool,
+    /// References take only one digit, so `$10` is `$1` followed by `0`
+    single_digit: bool,
+}
 
-        if self.registry.is_defined(name) {
-            if args.len() > 1 {
-                let result = self.expand_token(&args[1], parent_args, depth)?;
-                Ok(result.trim().to_string())
-            } else {
-                Ok(String::new())
-            }
-        } else if args.len() > 2 {
-            let result = self.expand_token(&args[2], parent_args, depth)?;
-            Ok(result.trim().to_string())
-        } else {
-            Ok(String::new())
+/// `ifelse` as in GNU m4: compare arguments as they are, and return nothing
+/// when no comparison matches and no default is left
+fn expand_gnu_ifelse(mut args: &[String]) -> &str {
+    // A single argument is a comment
+    if args.len() < 3 {
+        return "";
+    }
+    loop {
+        if args[0] == args[1] {
+            return &args[2];
+        }
+        match args.len() {
+            3 => return "",
+            // A fifth argument is ignored
+            4 | 5 => return &args[3],
+            _ => args = &args[3..],
         }
     }
+}
 
-    /// Rescan: parse the expanded text and expand again
-    fn rescan(&mut self, text: &str, depth: usize) -> Result<String, String> {
-        if depth > self.max_depth {
-            return Err("Maximum expansion depth exceeded".to_string());
-        }
+/// Text standing for a failed call that was not read from the input, with
+/// its arguments expanded
+fn call_text(name: &str, args: &[String]) -> String {
+    if args.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}({})", name, args.join(", "))
+    }
+}
 
-        // Try to parse - if it fails, just return the text as-is
-        match M4Parser::parse_input(text) {
-            Ok(tokens) => self.expand_tokens_with_depth(&tokens, &[], depth),
-            Err(_) => Ok(text.to_string()),
+/// Replace `$0`, `$1`, ... in a macro body with the name and arguments of a
+/// call. Missing arguments are empty. With `rules.special`, `$#` is replaced
+/// with the number of arguments, `$*` with all of them separated by commas
+/// and `$@` with the same, each argument quoted.
+fn substitute(body: &str, name: &str, args: &[String], rules: BodyRules) -> String {
+    let mut result = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let mut digits = after.bytes().take_while(u8::is_ascii_digit).count();
+        if rules.single_digit {
+            digits = digits.min(1);
+        }
+        let special = after
+            .chars()
+            .next()
+            .filter(|c| matches!(c, '#' | '*' | '@'));
+        match special {
+            Some(c) if rules.special => {
+                match c {
+                    '#' => result.push_str(&args.len().to_string()),
+                    '*' => result.push_str(&args.join(",")),
+                    _ => {
+                        let quoted: Vec<_> = args.iter().map(|arg| format!("`{}'", arg)).collect();
+                        result.push_str(&quoted.join(","));
+                    }
+                }
+                rest = &after[1..];
+                continue;
+            }
+            _ if digits == 0 => result.push('$'),
+            _ => match after[..digits].parse::<usize>() {
+                Ok(0) => result.push_str(name),
+                Ok(n) => result.push_str(args.get(n - 1).map_or("", String::as_str)),
+                Err(_) => {}
+            },
         }
+        rest = &after[digits..];
     }
+    result.push_str(rest);
+    result
 }
 
+/// Number of bytes `ExpandingReader` pulls from its inner reader at a time
+const CHUNK_SIZE: usize = 8 * 1024;
+
 /// A reader wrapper that expands M4 macros on-the-fly
+///
+/// Input is read in bounded chunks and expanded as it arrives; only an
+/// unfinished token or macro call is held back until more input arrives.
+///
+/// Expansion failures are returned as [`io::Error`]s wrapping the
+/// [`M4Error`], which can be recovered with `get_ref` and `downcast_ref`.
 pub struct ExpandingReader<R: Read> {
     inner: R,
-    registry: MacroRegistry,
+    expander: Expander,
+    /// Trailing bytes of a character split across chunks
+    pending: Vec<u8>,
     buffer: Vec<u8>,
     buffer_pos: usize,
     done: bool,
@@ -358,43 +2073,68 @@ pub struct ExpandingReader<R: Read> {
 
 impl<R: Read> ExpandingReader<R> {
     pub fn new(inner: R, registry: MacroRegistry) -> Self {
+        Self::with_expander(inner, Expander::new(registry))
+    }
+
+    /// Expand with a configured expander instead of a default one
+    pub fn with_expander(inner: R, expander: Expander) -> Self {
         Self {
             inner,
-            registry,
+            expander,
+            pending: Vec::new(),
             buffer: Vec::new(),
             buffer_pos: 0,
             done: false,
         }
     }
 
-    fn fill_buffer(&mut self) -> io::Result<()> {
-        if self.done {
-            return Ok(());
-        }
-
-        // Read the entire input (for now - could be optimized for streaming)
-        let mut input = String::new();
-        self.inner.read_to_string(&mut input)?;
+    /// Give back the expander, with any definitions made by the input
+    pub fn into_expander(self) -> Expander {
+        self.expander
+    }
 
-        let mut expander = Expander::new(self.registry.clone());
-        match expander.expand(&input) {
-            Ok(expanded) => {
-                self.buffer = expanded.into_bytes();
-                self.buffer_pos = 0;
-            }
-            Err(e) => {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        self.buffer_pos = 0;
+
+        let start = self.pending.len();
+        self.pending.resize(start + CHUNK_SIZE, 0);
+        let read = loop {
+            match self.inner.read(&mut self.pending[start..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result,
             }
+        };
+        self.pending.truncate(start + *read.as_ref().unwrap_or(&0));
+        if read? == 0 {
+            self.done = true;
         }
 
-        self.done = true;
+        // A multi-byte character may be split across chunks
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            // Only the trailing, incomplete character is invalid
+            Err(e) if e.error_len().is_none() && !self.done => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        let text = std::str::from_utf8(&self.pending[..valid])
+            .expect("prefix up to valid_up_to is valid UTF-8");
+
+        let mut expanded = self.expander.feed(text);
+        if self.done {
+            expanded = expanded.and_then(|mut output| {
+                output.push_str(&self.expander.finish()?);
+                Ok(output)
+            });
+        }
+        self.pending.drain(..valid);
+        self.buffer = expanded?.into_bytes();
         Ok(())
     }
 }
 
 impl<R: Read> Read for ExpandingReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.buffer_pos >= self.buffer.len() && !self.done {
+        while self.buffer_pos >= self.buffer.len() && !self.done {
             self.fill_buffer()?;
         }
 
@@ -414,7 +2154,10 @@ impl<R: Read> Read for ExpandingReader<R> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::CallChain;
+    use crate::parser::M4Parser;
     use std::borrow::Cow;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn test_simple_define() {
@@ -561,6 +2304,1068 @@ world"#;
         assert_eq!(output, "hello bar world");
     }
 
+    /// Reader that hands out its input a few bytes at a time, then fails
+    /// instead of reporting EOF when `fail_at_end` is set
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+        fail_at_end: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() && self.fail_at_end {
+                return Err(io::Error::other("connection reset"));
+            }
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_expanding_reader_split_input() {
+        let input = "define(`greet', `Hello $1!')dnl set up\n\
+                     greet(`big\n  world') # comment\n\
+                     `quoted foo' greet(ifelse(a, a, yes, no)) héllo dnl x\ndone\n\
+                     define(`open', `greet(')open(x)y)z\n";
+        let expected = Expander::new(MacroRegistry::new()).expand(input).unwrap();
+
+        for step in 1..8 {
+            let trickle = Trickle {
+                data: input.as_bytes(),
+                step,
+                fail_at_end: false,
+            };
+            let mut reader = ExpandingReader::new(trickle, MacroRegistry::new());
+            let mut output = String::new();
+            reader.read_to_string(&mut output).unwrap();
+            assert_eq!(output, expected, "split every {} bytes", step);
+        }
+    }
+
+    #[test]
+    fn test_expanding_reader_error() {
+        let mut reader = ExpandingReader::new("ok `open".as_bytes(), MacroRegistry::new());
+        let error = reader.read_to_string(&mut String::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let inner = error.get_ref().unwrap().downcast_ref::<M4Error>().unwrap();
+        assert!(matches!(inner, M4Error::UnexpectedEof { .. }));
+    }
+
+    #[test]
+    fn test_expanding_reader_streams_before_eof() {
+        let mut registry = MacroRegistry::new();
+        registry.load("define(`foo', `bar')").unwrap();
+
+        // Everything up to the unfinished quote is available before the
+        // inner reader fails
+        let trickle = Trickle {
+            data: b"hello foo world `unfinished",
+            step: 3,
+            fail_at_end: true,
+        };
+        let mut reader = ExpandingReader::new(trickle, registry);
+        let mut output = Vec::new();
+        let mut buf = [0u8; 64];
+        let err = loop {
+            match reader.read(&mut buf) {
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.to_string(), "connection reset");
+        assert_eq!(String::from_utf8(output).unwrap(), "hello bar world ");
+    }
+
+    #[test]
+    fn test_feed_chunks() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        let mut output = String::new();
+
+        output.push_str(&expander.feed("define(`greet', `Hel").unwrap());
+        assert_eq!(output, "");
+        output.push_str(&expander.feed("lo $1!')gre").unwrap());
+        // The name may continue in the next chunk
+        assert_eq!(expander.pending(), "gre");
+        output.push_str(&expander.feed("et(Wor").unwrap());
+        assert_eq!(output, "");
+        output.push_str(&expander.feed("ld) and `gre").unwrap());
+        assert_eq!(output, "Hello World! and ");
+        output.push_str(&expander.feed("et' ").unwrap());
+        assert_eq!(output, "Hello World! and greet ");
+        output.push_str(&expander.feed("greet").unwrap());
+        output.push_str(&expander.finish().unwrap());
+        assert_eq!(output, "Hello World! and greet Hello !");
+        assert_eq!(expander.pending(), "");
+    }
+
+    #[test]
+    fn test_finish_unterminated_quote() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        assert_eq!(expander.feed("one `two").unwrap(), "one ");
+        assert!(expander.finish().is_err());
+    }
+
+    #[test]
+    fn test_expansion_rescanned_with_following_input() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .load("define(`wrap', `[$1]')define(`call', `wrap(')")
+            .unwrap();
+
+        let mut expander = Expander::new(registry);
+        // `call` expands to an unfinished call of `wrap`, which picks up the
+        // rest of its arguments from the input that followed `call`
+        assert_eq!(expander.expand("call x) $0").unwrap(), "[x] $0");
+    }
+
+    #[test]
+    fn test_expansion_scanned_once() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .load("define(`foo', `bar')define(`quoted', ``foo'')define(`name', ``$0'')")
+            .unwrap();
+
+        let mut expander = Expander::new(registry);
+        assert_eq!(expander.expand("quoted name").unwrap(), "foo name");
+    }
+
+    #[test]
+    fn test_comments_copied() {
+        let mut registry = MacroRegistry::new();
+        registry.load("define(`foo', `bar')").unwrap();
+
+        let mut expander = Expander::new(registry).dialect(Dialect::Gnu);
+        assert_eq!(
+            expander.expand("foo # foo (\nfoo(a # b,\n)").unwrap(),
+            "bar # foo (\nbar"
+        );
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let source = "define(`wrap', `[$1]')define(`deep', `wrap(deep)')";
+        let nested = |depth| "wrap(".repeat(depth) + &")".repeat(depth);
+
+        let mut expander = Expander::new(MacroRegistry::new()).nesting_limit(5);
+        expander.expand(source).unwrap();
+        assert_eq!(expander.expand(&nested(5)).unwrap(), "[[[[[]]]]]");
+        assert!(matches!(
+            expander.expand(&nested(6)),
+            Err(M4Error::NestingLimit { limit: 5, .. })
+        ));
+        // Each `deep` is called while collecting the arguments of `wrap`
+        let error = expander.expand("deep").unwrap_err();
+        let names: Vec<_> = error.call_stack().iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["wrap", "wrap", "wrap", "wrap", "wrap", "deep"]);
+        assert_eq!(
+            error.to_string(),
+            "1:5: nesting limit of 5 exceeded; call chain: \
+             wrap() at 1:5 repeated 5 times -> deep at 1:5"
+        );
+
+        // Nesting is not bounded by the Rust stack
+        let mut expander = Expander::new(MacroRegistry::new()).nesting_limit(0);
+        expander.expand(source).unwrap();
+        let depth = 2_000;
+        assert_eq!(
+            expander.expand(&nested(depth)).unwrap(),
+            "[".repeat(depth) + &"]".repeat(depth)
+        );
+    }
+
+    /// Definitions of `l0` to `l9`, each expanding to ten calls of the one
+    /// below: `l9` expands to gigabytes of text
+    fn laughs() -> String {
+        let mut laughs = String::from("define(`l0', `lol')");
+        for level in 1..=9 {
+            let call = format!("l{} ", level - 1);
+            laughs += &format!("define(`l{}', `{}')", level, call.repeat(10));
+        }
+        laughs
+    }
+
+    #[test]
+    fn test_limits() {
+        let laughs = laughs();
+        let limited = |limits| {
+            let mut expander = Expander::new(MacroRegistry::new()).limits(limits);
+            expander.expand(&laughs).unwrap();
+            expander
+        };
+        let limit_of = |error: M4Error| match error {
+            M4Error::LimitExceeded { limit, max, .. } => (limit, max),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let mut expander = limited(ExpanderLimits {
+            output_bytes: Some(10_000),
+            ..ExpanderLimits::default()
+        });
+        let mut output = String::new();
+        let error = expander.expand_into("l9", &mut output).unwrap_err();
+        assert_eq!(limit_of(error), (Limit::OutputBytes, 10_000));
+        assert!(output.starts_with("lol lol") && output.len() <= 10_001);
+        // The count starts over with each input
+        assert_eq!(expander.expand("l2").unwrap().len(), 410);
+
+        let mut expander = limited(ExpanderLimits {
+            invocations: Some(1_000),
+            ..ExpanderLimits::default()
+        });
+        let error = expander.expand("x l9").unwrap_err();
+        assert_eq!(error.call_stack().last().unwrap().name, "l0");
+        assert_eq!(limit_of(error), (Limit::Invocations, 1_000));
+        let expansion = expander.expand_recovering("l9", Recovery::Original);
+        assert!(matches!(
+            expansion.errors[..],
+            [M4Error::LimitExceeded {
+                limit: Limit::Invocations,
+                ..
+            }]
+        ));
+
+        let mut expander = limited(ExpanderLimits {
+            argument_bytes: Some(100),
+            ..ExpanderLimits::default()
+        });
+        expander.expand("define(`id', `$1')").unwrap();
+        assert_eq!(expander.expand("id(l1)").unwrap().len(), 40);
+        let error = expander.expand("id(id(l2))").unwrap_err();
+        let names: Vec<_> = error.call_stack().iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["id", "id"]);
+        assert_eq!(limit_of(error), (Limit::ArgumentBytes, 100));
+
+        let mut expander = Expander::new(MacroRegistry::new()).limits(ExpanderLimits {
+            definitions: Some(2),
+            ..ExpanderLimits::default()
+        });
+        expander.expand("define(`a', 1)define(`b', 2)").unwrap();
+        expander.expand("define(`a', 3)").unwrap();
+        let error = expander.expand("define(`c', 4)").unwrap_err();
+        assert_eq!(error.to_string(), "1:15: limit of 2 definitions exceeded");
+        assert!(!expander.is_defined("c"));
+    }
+
+    #[test]
+    fn test_interruption() {
+        let laughs = &laughs();
+
+        let token = CancellationToken::new();
+        let mut expander = Expander::new(MacroRegistry::new()).cancellation_token(token.clone());
+        expander.expand(laughs).unwrap();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            token.cancel();
+        });
+        let mut output = String::new();
+        let error = expander.expand_into("l9", &mut output).unwrap_err();
+        canceller.join().unwrap();
+        let M4Error::Cancelled { progress, .. } = error else {
+            panic!("unexpected {:?}", error);
+        };
+        assert!(progress.invocations > 0);
+        assert_eq!(progress.output_bytes, output.len());
+        // The token stays cancelled
+        assert!(matches!(
+            expander.expand("text"),
+            Err(M4Error::Cancelled { .. })
+        ));
+
+        let deadline = Instant::now() + std::time::Duration::from_millis(20);
+        let mut expander = Expander::new(MacroRegistry::new()).deadline(deadline);
+        expander.expand(laughs).unwrap();
+        let error = expander.expand("x l9").unwrap_err();
+        assert!(Instant::now() >= deadline);
+        assert!(error.to_string().contains(": expansion timed out after "));
+
+        let reader = ExpandingReader::with_expander("l9".as_bytes(), expander);
+        let error = io::read_to_string(reader).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let root = std::env::temp_dir().join(format!("m4rs-include-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("lib.m4"), "define(`greet', `Hi $1')dnl\n").unwrap();
+        let outside = root.with_extension("m4");
+        std::fs::write(&outside, "secret").unwrap();
+
+        let policy = SandboxPolicy::new().file_root(&root);
+        let mut expander = Expander::new(MacroRegistry::new()).sandbox(policy);
+        assert_eq!(
+            expander.expand("include(`lib.m4')greet(you)").unwrap(),
+            "Hi you"
+        );
+        assert_eq!(
+            expander.expand("sinclude(`missing.m4')include").unwrap(),
+            ""
+        );
+        let error = expander.expand("include(`missing.m4')").unwrap_err();
+        assert!(matches!(error, M4Error::InvalidArgument { .. }));
+        let escape = format!("../m4rs-include-{}.m4", std::process::id());
+        let error = expander
+            .expand(&format!("sinclude(`{}')", escape))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "1:{}: sinclude: `{}' is outside the allowed directory",
+                escape.len() + 13,
+                escape
+            )
+        );
+
+        let policy = SandboxPolicy::safer();
+        let mut expander = Expander::new(MacroRegistry::new()).sandbox(policy);
+        let error = expander.expand("m4exit(1)").unwrap_err();
+        assert_eq!(error.to_string(), "1:10: m4exit: exiting is disabled");
+        let expansion =
+            expander.expand_recovering("[include(`lib.m4')]", Recovery::Placeholder("?".into()));
+        assert_eq!(expansion.output, "[?]");
+        assert!(matches!(
+            &expansion.errors[..],
+            [M4Error::PolicyViolation { macro_name, .. }] if macro_name == "include"
+        ));
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_classic_comments() {
+        // The output of 0.1, which dropped comments
+        let cases = [
+            ("define(`x', `y')x # x\nx", "y \ny"),
+            ("define(`n', `$#')n\n", "$#\n"),
+            ("define(`n', `$#')n", "$#"),
+            ("a#b\nc", "a\nc"),
+            ("1#2 3\n4", "1#2 3\n4"),
+            ("# only comment", ""),
+            ("text # comment `with quote\nnext", "text \nnext"),
+            ("define(`f', `[$1]')f(a # b\n)", "[a \n]"),
+            ("define(`g', `x # y')g\nz", "x \nz"),
+            ("  # indented\n  x", "  \n  x"),
+            ("`#' quoted", "# quoted"),
+            ("define(`c', `#')c z\nw", " z\nw"),
+        ];
+        for (input, expected) in cases {
+            let mut classic = Expander::new(MacroRegistry::new());
+            assert_eq!(classic.expand(input).unwrap(), expected, "{}", input);
+        }
+
+        // Split input reads the same
+        let mut classic = Expander::new(MacroRegistry::new());
+        let mut output = classic.feed("1").unwrap();
+        output += &classic.feed("#2 #").unwrap();
+        output += &classic.feed(" x\ny").unwrap();
+        output += &classic.finish().unwrap();
+        assert_eq!(output, "1#2 \ny");
+    }
+
+    #[test]
+    fn test_dialects() {
+        let input = "define(` x ', `X')ifelse(` a', `a', yes, no) ifdef(`x', ` x ')|define|$#\n";
+        let mut classic = Expander::new(MacroRegistry::new());
+        assert_eq!(classic.expand(input).unwrap(), "yes X||$#\n");
+        assert!(classic.is_defined("x"));
+
+        let mut gnu = Expander::new(MacroRegistry::new()).dialect(Dialect::Gnu);
+        assert_eq!(gnu.expand(input).unwrap(), "no |define|$#\n");
+        assert!(gnu.is_defined(" x "));
+        gnu.expand("define(`count', `$#:$*:$@')").unwrap();
+        assert_eq!(gnu.expand("count(a,`b,c')").unwrap(), "2:a,b,c:a,b,c");
+    }
+
+    #[test]
+    fn test_bsd_dialect() {
+        use crate::vfs::MemoryFs;
+
+        let files = MemoryFs::new().with_file("raw.m4", "define(`x', `X')x");
+        let mut bsd = Expander::new(MacroRegistry::new())
+            .dialect(Dialect::Bsd)
+            .file_provider(files);
+        assert_eq!(
+            bsd.expand("paste(`raw.m4') spaste(`none.m4')|").unwrap(),
+            "define(`x', `X')x |"
+        );
+        assert!(!bsd.is_defined("x"));
+        assert!(bsd.expand("paste(`none.m4')").is_err());
+        assert!(!bsd.is_defined("__gnu__"));
+        assert!(bsd.is_defined("expr") && bsd.is_defined("traceon"));
+        bsd.expand("define(`ten', `$10')").unwrap();
+        assert_eq!(bsd.expand("ten(a)").unwrap(), "a0");
+        assert_eq!(bsd.expand("ifelse(` a', `a', yes, no)").unwrap(), "no");
+
+        let mut gnu = Expander::new(MacroRegistry::new()).dialect(Dialect::Gnu);
+        assert!(!gnu.is_defined("paste") && gnu.is_defined("__gnu__"));
+        assert!(!gnu.is_defined("expr") && gnu.is_defined("eval"));
+        assert_eq!(Dialect::from_name("bsd"), Some(Dialect::Bsd));
+
+        // `builtin` only reaches the builtins of the dialect
+        assert_eq!(bsd.expand("builtin(`expr', `1+1')").unwrap(), "2");
+        assert!(matches!(
+            gnu.expand("builtin(`paste', `raw.m4')"),
+            Err(M4Error::UnknownBuiltin { name, .. }) if name == "paste"
+        ));
+        assert!(matches!(
+            bsd.expand("builtin(`__gnu__')"),
+            Err(M4Error::UnknownBuiltin { .. })
+        ));
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut gnu = Expander::new(MacroRegistry::new()).dialect(Dialect::Gnu);
+        assert_eq!(
+            gnu.expand("eval(`2**3 + 7 % 4') eval(`-1 < 0 && !0')")
+                .unwrap(),
+            "11 1"
+        );
+        assert_eq!(
+            gnu.expand("eval(`666', `6', `10') eval(`255', `16') eval(`3', `1')")
+                .unwrap(),
+            "0000003030 ff 111"
+        );
+        assert!(matches!(
+            gnu.expand("eval(`1/0')"),
+            Err(M4Error::InvalidArgument { macro_name, message, .. })
+                if macro_name == "eval" && message == "divide by zero in `1/0'"
+        ));
+        assert!(gnu.expand("eval(`1', `37')").is_err());
+        assert_eq!(gnu.expand("eval eval(`1', `x')|").unwrap(), "eval |");
+
+        // BSD m4 also calls it `expr`, and has no radix 1
+        let mut bsd = Expander::new(MacroRegistry::new()).dialect(Dialect::Bsd);
+        assert_eq!(bsd.expand("expr(`6*7') eval(`5', `2')").unwrap(), "42 101");
+        assert!(matches!(
+            bsd.expand("eval(`3', `1')"),
+            Err(M4Error::InvalidArgument { message, .. })
+                if message == "radix 1 out of range (valid ranges are 2 to 36)"
+        ));
+
+        // Classic input keeps these names as text
+        let mut classic = Expander::new(MacroRegistry::new());
+        assert_eq!(
+            classic.expand("eval(1) __line__").unwrap(),
+            "eval(1) __line__"
+        );
+
+        let (expander, log) = warning_log();
+        let mut expander = expander.dialect(Dialect::Gnu);
+        assert_eq!(expander.expand("eval(`')").unwrap(), "0");
+        assert_eq!(log.lock().unwrap()[0].kind, WarningKind::NonNumeric);
+    }
+
+    #[test]
+    fn test_m4wrap() {
+        let input = "m4wrap(`a')m4wrap(`b', `c')m4wrap(`-m4wrap(`d')')text ";
+        let mut gnu = Expander::new(MacroRegistry::new()).dialect(Dialect::Gnu);
+        assert_eq!(gnu.expand(input).unwrap(), "text -b cad");
+        // The text is read once, at the end of the input that saved it
+        assert_eq!(gnu.expand("more").unwrap(), "more");
+
+        let mut bsd = Expander::new(MacroRegistry::new()).dialect(Dialect::Bsd);
+        assert_eq!(bsd.expand(input).unwrap(), "text ab c-d");
+
+        // Held text is read after every input, when asked for
+        let mut held = Expander::new(MacroRegistry::new())
+            .dialect(Dialect::Gnu)
+            .hold_wrapped(true);
+        assert_eq!(held.expand("m4wrap(`x')1 ").unwrap(), "1 ");
+        assert_eq!(held.expand("define(`x', `X')2 ").unwrap(), "2 ");
+        let mut output = String::new();
+        held.expand_wrapped_into(&mut output).unwrap();
+        assert_eq!(output, "X");
+
+        // Exiting discards the saved text
+        let mut output = String::new();
+        assert!(
+            gnu.expand_into("m4wrap(`lost')m4exit", &mut output)
+                .is_err()
+        );
+        assert_eq!(gnu.expand("kept").unwrap(), "kept");
+    }
+
+    #[test]
+    fn test_trace() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&log);
+        let mut expander = Expander::new(MacroRegistry::new())
+            .dialect(Dialect::Gnu)
+            .trace_sink(move |trace: Trace| sink.lock().unwrap().push(trace.to_string()));
+        expander
+            .expand("define(`f', `[$1]')define(`g', `f($1)')traceon(`f')g(f(`x'))")
+            .unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["-2- f(`x') -> `[x]'", "-1- f(`[x]') -> `[[x]]'"]
+        );
+        assert!(expander.is_traced("f") && !expander.is_traced("g"));
+
+        log.lock().unwrap().clear();
+        expander
+            .expand("traceoff(`f')traceon g(`y')traceoff f(`z')")
+            .unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "-1- g(`y') -> `f(y)'",
+                "-1- f(`y') -> `[y]'",
+                "-1- traceoff"
+            ]
+        );
+        assert!(!expander.is_traced("f"));
+
+        expander.trace("f", true);
+        log.lock().unwrap().clear();
+        expander.expand("f").unwrap();
+        assert_eq!(*log.lock().unwrap(), ["-1- f -> `[]'"]);
+    }
+
+    #[test]
+    fn test_file_and_line() {
+        let mut expander = Expander::new(MacroRegistry::new()).dialect(Dialect::Gnu);
+        let file = expander.add_source("in.m4", "");
+        expander.set_file(file);
+        assert_eq!(
+            expander.expand("__file__:__line__\n__line__").unwrap(),
+            "in.m4:1\n2"
+        );
+        // Reported where the name was read, not where the call ended
+        assert_eq!(
+            expander.expand("define(`l', `__line__')\nl").unwrap(),
+            "\n2"
+        );
+    }
+
+    #[test]
+    fn test_changeword() {
+        let words = WordSyntax::new("@([A-Za-z_]+)@").unwrap();
+        let mut expander = Expander::new(MacroRegistry::new()).word_syntax(words);
+        expander.registry.define_source("NAME".to_string(), "World");
+        assert_eq!(
+            expander.expand("Hello @NAME@, NAME @other@(1)").unwrap(),
+            "Hello World, NAME @other@(1)"
+        );
+        expander.expand("@define@(`x', `X')").unwrap();
+        assert_eq!(expander.expand("@x@@x@ x").unwrap(), "XX x");
+
+        // Words and their end are found across pushed-back text
+        let mut gnu = Expander::new(MacroRegistry::new());
+        gnu.expand("changeword(`[_a-zA-Z0-9]+')define(`1st', `one')")
+            .unwrap();
+        assert_eq!(gnu.expand("1st 2nd").unwrap(), "one 2nd");
+        assert!(gnu.current_word_syntax().is_some());
+        gnu.expand("changeword(`[_a-zA-Z][_a-zA-Z0-9]*')").unwrap();
+        assert!(gnu.current_word_syntax().is_none());
+        assert!(gnu.expand("changeword(`(')").is_err());
+
+        let bsd = Expander::new(MacroRegistry::new()).dialect(Dialect::Bsd);
+        assert!(!bsd.is_defined("changeword"));
+    }
+
+    #[test]
+    fn test_traditional() {
+        let mut gnu = Expander::new(MacroRegistry::new());
+        gnu.expand("define(`ten', `$10')").unwrap();
+        assert_eq!(gnu.expand("ten(a)").unwrap(), "");
+        assert!(gnu.is_defined("__gnu__"));
+
+        let mut traditional = Expander::new(MacroRegistry::new()).traditional(true);
+        traditional.expand("define(`ten', `$10')").unwrap();
+        assert_eq!(traditional.expand("ten(a)").unwrap(), "a0");
+        assert!(!traditional.is_defined("__gnu__"));
+        assert_eq!(
+            traditional.expand("builtin(`define', `x')").unwrap(),
+            "builtin(define, x)"
+        );
+        if cfg!(unix) {
+            assert!(traditional.is_defined("unix"));
+            assert!(!traditional.is_defined("__unix__"));
+        }
+
+        let prefixed = Expander::new(MacroRegistry::new())
+            .traditional(true)
+            .prefix_builtins(true);
+        assert_eq!(prefixed.builtin_name(Builtin::Unix), "m4_unix");
+        assert!(prefixed.is_defined("m4_define") && !prefixed.is_defined("m4_builtin"));
+    }
+
+    #[test]
+    fn test_unknown_passthrough() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        expander
+            .expand("define(`a', `A')define(`id', `$1')")
+            .unwrap();
+        // Only macros inside the parentheses are expanded; quotes are removed
+        // as anywhere else
+        let input = "printf(a,b);\nf( a ,\t`b' ) g()\n";
+        assert_eq!(
+            expander.expand(input).unwrap(),
+            "printf(A,b);\nf( A ,\tb ) g()\n"
+        );
+        // Commas inside them do not split the arguments of an enclosing call
+        assert_eq!(expander.expand("id(f(a,  b))").unwrap(), "f(A,  b)");
+        // Nor does an unbalanced parenthesis leave anything open
+        assert_eq!(expander.expand("f(a").unwrap(), "f(A");
+    }
+
+    #[test]
+    fn test_native_builtins() {
+        let (mut expander, log) = warning_log();
+        expander.expand("define(`x', `X')").unwrap();
+        expander.define_native(
+            "upcase",
+            NativeFlags::default(),
+            |cx: &mut CallContext<'_>, args: &[String]| {
+                cx.push_back(&args.concat().to_uppercase());
+                Ok(())
+            },
+        );
+        let flags = NativeFlags {
+            requires_arguments: true,
+            raw_arguments: true,
+        };
+        expander.define_native(
+            "show",
+            flags,
+            |cx: &mut CallContext<'_>, args: &[String]| {
+                if args.len() > 1 {
+                    cx.warn(WarningKind::ExcessArguments, "show takes one argument")?;
+                }
+                let text = format!("<{}>", args[0]);
+                cx.output(&text);
+                Ok(())
+            },
+        );
+        expander.define_native(
+            "count",
+            NativeFlags::default(),
+            |cx: &mut CallContext<'_>, args: &[String]| {
+                if !args.is_empty() {
+                    return Err(cx.error("takes no arguments"));
+                }
+                let count = cx.registry().len().to_string();
+                cx.push_back(&count);
+                Ok(())
+            },
+        );
+
+        // Pushed back text is rescanned, output text is not
+        assert_eq!(expander.expand("upcase(`x')").unwrap(), "X");
+        assert_eq!(expander.expand("show(`x')").unwrap(), "<x>");
+        // Raw arguments keep macro names; a bare name is text
+        assert_eq!(
+            expander.expand("show(x (x), `y')show").unwrap(),
+            "<x (x)>show"
+        );
+        assert_eq!(log.lock().unwrap()[0].message, "show takes one argument");
+        assert_eq!(expander.expand("upcase(show(x))").unwrap(), "<X>");
+        assert_eq!(expander.expand("count").unwrap(), "1");
+
+        let error = expander.expand("\ncount()").unwrap_err();
+        assert_eq!(error.to_string(), "2:8: count: takes no arguments");
+        assert!(expander.is_defined("show"));
+        assert!(expander.undefine("show"));
+        assert_eq!(expander.expand("show(x)").unwrap(), "show(X)");
+
+        // Defining the name replaces the native
+        expander.expand("define(`upcase', `[$1]')").unwrap();
+        assert_eq!(expander.expand("upcase(`y')").unwrap(), "[y]");
+    }
+
+    #[test]
+    fn test_resolver() {
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&asked);
+        let mut expander = Expander::new(MacroRegistry::new()).resolver(move |name: &str| {
+            log.lock().unwrap().push(name.to_owned());
+            match name {
+                "user" => Resolution::Define("alice".into()),
+                "tick" => Resolution::Body("[$1]".into()),
+                _ => Resolution::Decline,
+            }
+        });
+
+        let output = expander
+            .expand("user tick(1) user tick(2) plain(x)")
+            .unwrap();
+        assert_eq!(output, "alice [1] alice [2] plain(x)");
+        // A definition is cached, so only its rescanned expansion is asked
+        // about again
+        assert_eq!(
+            *asked.lock().unwrap(),
+            ["user", "alice", "tick", "alice", "tick", "plain", "x"]
+        );
+        assert_eq!(expander.registry.get("user"), Some("alice"));
+        assert!(!expander.is_defined("tick"));
+    }
+
+    #[test]
+    fn test_file_provider() {
+        use crate::vfs::MemoryFs;
+
+        let files = MemoryFs::new()
+            .with_file(
+                "lib/greet.m4",
+                "define(`greet', `Hi $1')dnl\ninclude(`lib/name.m4')",
+            )
+            .with_file("lib/name.m4", "define(`name', `you')dnl\n");
+        let mut registry = MacroRegistry::new();
+        registry.load_file_from(&files, "lib/name.m4").unwrap();
+        assert_eq!(registry.get("name"), Some("you"));
+        assert!(matches!(
+            registry.load_file_from(&files, "name.m4"),
+            Err(M4Error::Io { .. })
+        ));
+
+        let policy = SandboxPolicy::new().file_root("lib");
+        let mut expander = Expander::new(MacroRegistry::new())
+            .file_provider(files)
+            .sandbox(policy);
+        // Names are relative to the root, so the nested include misses
+        let error = expander.expand("include(`greet.m4')").unwrap_err();
+        assert!(error.to_string().contains("cannot open `lib/name.m4'"));
+        let mut expander = Expander::new(MacroRegistry::new())
+            .file_provider(MemoryFs::new().with_file("a.m4", "define(`a', `A')"));
+        assert_eq!(expander.expand("include(`./a.m4')a").unwrap(), "A");
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        let error = expander.expand("one\ntwo `three").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "2:11: end of file in string opened at 2:5"
+        );
+        // Classic input drops comments, so only m4's comments can be unclosed
+        let mut gnu = Expander::new(MacroRegistry::new()).dialect(Dialect::Gnu);
+        let error = gnu.expand("a\n# note").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "2:7: end of file in comment opened at 2:1"
+        );
+
+        expander.set_file(FileId(1));
+        let error = expander
+            .expand("define(`f', `$1')\n  f(a,\n f(b")
+            .unwrap_err();
+        let location = |offset, line, column| Location {
+            offset,
+            line,
+            column,
+        };
+        assert_eq!(error.location(), Some(location(29, 3, 5)));
+        // The innermost unfinished call is reported as the unclosed one
+        assert!(matches!(
+            error,
+            M4Error::UnexpectedEof {
+                construct: Unclosed::ArgumentList,
+                opened,
+                ..
+            } if opened == location(26, 3, 2)
+        ));
+
+        // Both calls point at the definition of `f` made by the same input
+        let definition = Span {
+            file: FileId(1),
+            start: location(0, 1, 1),
+            end: location(17, 1, 18),
+        };
+        let call = |location, arguments: &[&str]| CallSite {
+            name: "f".into(),
+            file: FileId(1),
+            location,
+            definition: Some(definition),
+            arguments: arguments.iter().map(|arg| arg.to_string()).collect(),
+        };
+        assert_eq!(
+            error.call_stack(),
+            [
+                call(location(20, 2, 3), &["a", ""]),
+                call(location(26, 3, 2), &["b"])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_definition_span() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        expander
+            .expand("dnl\ndefine(`greet', `Hello\n$1')")
+            .unwrap();
+        let span = expander.registry.definition_span("greet").unwrap();
+        assert_eq!((span.start.line, span.start.column), (2, 1));
+        assert_eq!(span.end.line, 3);
+
+        // Definitions not made by expanded input have no location
+        let mut registry = MacroRegistry::new();
+        registry.define_source("plain".to_string(), "text");
+        registry.define("tokens".to_string(), vec![Token::Positional(1)]);
+        assert_eq!(registry.definition_span("plain"), None);
+        assert_eq!(registry.definition_span("tokens"), None);
+    }
+
+    #[test]
+    fn test_builtin_errors() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        let mut output = String::new();
+        let error = expander
+            .expand_into("before m4exit(3) after", &mut output)
+            .unwrap_err();
+        assert!(matches!(error, M4Error::Abort { status: 3, .. }));
+        assert_eq!(output, "before ");
+
+        // A status that is not a number is a warning, and a failure
+        assert!(matches!(
+            expander.expand("m4exit(`x')"),
+            Err(M4Error::Abort { status: 1, .. })
+        ));
+        assert!(matches!(
+            expander.expand("m4exit(300)"),
+            Err(M4Error::InvalidArgument { macro_name, .. }) if macro_name == "m4exit"
+        ));
+
+        expander.undefine("define");
+        assert_eq!(
+            expander
+                .expand("builtin(`define', `x', `1')x define")
+                .unwrap(),
+            "1 define"
+        );
+        assert!(matches!(
+            expander.expand("builtin(`nosuch')"),
+            Err(M4Error::UnknownBuiltin { name, .. }) if name == "nosuch"
+        ));
+    }
+
+    /// Expander whose warnings are collected in the returned list
+    fn warning_log() -> (Expander, Arc<Mutex<Vec<Warning>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&log);
+        let expander = Expander::new(MacroRegistry::new())
+            .warning_sink(move |warning| sink.lock().unwrap().push(warning));
+        (expander, log)
+    }
+
+    #[test]
+    fn test_warnings() {
+        let (mut expander, log) = warning_log();
+        let output = expander
+            .expand(
+                "define(`a')define(`b', 1, 2)ifdef(`b')dnl(x)\n\
+                 ifelse(`comment')\nifelse(a, b) ifelse define b\n",
+            )
+            .unwrap();
+        assert_eq!(output, "comment\na   1\n");
+        let warnings: Vec<_> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|w| (w.kind, w.message.clone()))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (
+                    WarningKind::TooFewArguments,
+                    "too few arguments to builtin `define'".to_string()
+                ),
+                (
+                    WarningKind::ExcessArguments,
+                    "excess arguments to builtin `define' ignored".to_string()
+                ),
+                (
+                    WarningKind::TooFewArguments,
+                    "too few arguments to builtin `ifdef'".to_string()
+                ),
+                (
+                    WarningKind::ExcessArguments,
+                    "excess arguments to builtin `dnl' ignored".to_string()
+                ),
+                (
+                    WarningKind::TooFewArguments,
+                    "too few arguments to builtin `ifelse'".to_string()
+                ),
+            ]
+        );
+        let first = log.lock().unwrap()[0].clone();
+        assert_eq!(first.location.offset, 0);
+        assert_eq!(first.stack.last().unwrap().name, "define");
+
+        // A disabled kind is not reported
+        log.lock().unwrap().clear();
+        let mut expander = expander.enable_warning(WarningKind::ExcessArguments, false);
+        expander.expand("dnl(x)\nm4exit(`x')").unwrap_err();
+        let warnings = log.lock().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::NonNumeric);
+    }
+
+    #[test]
+    fn test_fatal_warnings() {
+        let (expander, log) = warning_log();
+        let mut expander = expander.fatal_warnings(true);
+        let mut output = String::new();
+        let error = expander
+            .expand_into("a define(`x', 1, 2) b", &mut output)
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            M4Error::FatalWarning { warning } if warning.kind == WarningKind::ExcessArguments
+        ));
+        assert_eq!(
+            error.to_string(),
+            "1:3: excess arguments to builtin `define' ignored"
+        );
+        assert_eq!(output, "a ");
+        assert!(log.lock().unwrap().is_empty());
+
+        // Disabled warnings are not fatal
+        let mut expander = expander.enable_warning(WarningKind::ExcessArguments, false);
+        assert_eq!(expander.expand("define(`x', 1, 2)x").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_expand_recovering() {
+        let mut expander = Expander::new(MacroRegistry::new()).nesting_limit(5);
+        expander
+            .expand("define(`wrap', `[$1]')define(`deep', `wrap(deep)')")
+            .unwrap();
+
+        let input = "a builtin(`nosuch', x) b m4exit(300) c";
+        let expansion = expander.expand_recovering(input, Recovery::Placeholder("<?>".into()));
+        assert_eq!(expansion.output, "a <?> b <?> c");
+        assert!(matches!(
+            &expansion.errors[..],
+            [
+                M4Error::UnknownBuiltin { .. },
+                M4Error::InvalidArgument { .. },
+            ]
+        ));
+
+        let expansion = expander.expand_recovering(input, Recovery::Original);
+        assert_eq!(expansion.output, input);
+        assert_eq!(expansion.errors.len(), 2);
+        // The call is output exactly as written
+        let input = "[builtin(  `nosuch' ,`x'\n)]";
+        let expansion = expander.expand_recovering(input, Recovery::Original);
+        assert_eq!(expansion.output, input);
+        // A call from an expansion was never written, so it is rebuilt
+        expander
+            .expand("define(`bad', `builtin(`nosuch', `x')')")
+            .unwrap();
+        let expansion = expander.expand_recovering("bad", Recovery::Original);
+        assert_eq!(expansion.output, "builtin(nosuch, x)");
+
+        // Runaway recursion stops expansion
+        let expansion = expander.expand_recovering("a deep b", Recovery::Original);
+        assert_eq!(expansion.output, "a ");
+        assert!(matches!(
+            &expansion.errors[..],
+            [M4Error::NestingLimit { .. }]
+        ));
+
+        // The unread rest is output, and the unfinished call
+        let expansion = expander.expand_recovering("x wrap(a, `b) y", Recovery::Original);
+        assert_eq!(expansion.output, "x wrap(a, `b) y");
+        let expansion =
+            expander.expand_recovering("x wrap(a, `b) y", Recovery::Placeholder("?".into()));
+        assert_eq!(expansion.output, "x ?");
+        assert!(matches!(
+            &expansion.errors[..],
+            [M4Error::UnexpectedEof {
+                construct: Unclosed::String,
+                ..
+            }]
+        ));
+
+        // `m4exit` still stops expansion
+        let expansion = expander.expand_recovering("a m4exit(2) b", Recovery::Original);
+        assert_eq!(expansion.output, "a ");
+        assert!(matches!(
+            &expansion.errors[..],
+            [M4Error::Abort { status: 2, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_load_file_error() {
+        let error = MacroRegistry::new()
+            .load_file("does/not/exist.m4")
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            M4Error::Io { path, source }
+                if path.ends_with("exist.m4") && source.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_call_chain() {
+        let mut expander = Expander::new(MacroRegistry::new()).nesting_limit(7);
+        let error = expander
+            .expand(
+                "define(`x', `$1')define(`y', `$1')define(`a', `x(b)')define(`b', `y(a)')\n\
+                 y(`an argument that is much too long', a)",
+            )
+            .unwrap_err();
+        assert!(matches!(error, M4Error::NestingLimit { .. }));
+        assert_eq!(
+            CallChain(error.call_stack()).to_string(),
+            "[y(an argument that is ..., ) at 2:1 -> x() at 2:41] repeated 3 times \
+             -> y() at 2:41 -> a at 2:41"
+        );
+    }
+
+    #[test]
+    fn test_self_reference() {
+        let mut expander = Expander::new(MacroRegistry::new());
+        for source in [
+            "define(`a', `a')a",
+            "define(`a', `$0 again')x a(1)",
+            "define(`a', `b')define(`b', `a')a",
+        ] {
+            let error = expander.expand(source).unwrap_err();
+            assert!(
+                matches!(&error, M4Error::SelfReference { name, .. } if name == "a" || name == "b"),
+                "{}: {:?}",
+                source,
+                error
+            );
+        }
+
+        // Arguments may come from the input after the call
+        assert_eq!(
+            expander.expand("define(`f', `$1')f(`f')(`x')").unwrap(),
+            "x"
+        );
+
+        // A call with arguments, or a longer name, may well finish
+        assert_eq!(
+            expander
+                .expand("define(`a', `ifelse($1, `', `done', `a()')')a(x) define(`ab', `abc')ab")
+                .unwrap(),
+            "done abc"
+        );
+    }
+
+    #[test]
+    fn test_tail_recursion() {
+        // Each call ends by calling itself once its own arguments are known,
+        // so the nesting depth stays constant however many times it recurses
+        let mut expander = Expander::new(MacroRegistry::new());
+        expander
+            .expand("define(`upto', `ifelse(`$1', `$2', `done', `upto(`x$1', `$2')')')")
+            .unwrap();
+        let input = format!("upto(`', `{}')", "x".repeat(2000));
+        assert_eq!(expander.expand(&input).unwrap(), "done");
+    }
+
     #[test]
     fn test_recursive_argument_expansion() {
         // Test that arguments which are themselves macros get expanded first
@@ -619,4 +3424,45 @@ world"#;
         let result = expander.expand("feature_impl").unwrap();
         assert_eq!(result, "FEATURE_CODE");
     }
+
+    #[test]
+    fn test_prefixed_builtins() {
+        let registry = MacroRegistry::new();
+        let mut expander = Expander::new(registry).prefix_builtins(true);
+
+        let result = expander
+            .expand("m4_define(`greet', `Hello $1!')m4_ifelse(a, a, greet(World), no)")
+            .unwrap();
+        assert_eq!(result, "Hello World!");
+        assert!(expander.registry.is_defined("greet"));
+
+        // Unprefixed builtin names are plain text
+        let result = expander
+            .expand("define(`x', `y') ifdef(`greet', yes)")
+            .unwrap();
+        assert_eq!(result, "define(x, y) ifdef(greet, yes)");
+        assert!(!expander.registry.is_defined("x"));
+    }
+
+    #[test]
+    fn test_prefixed_predefined_macros() {
+        let mut expander = Expander::new(MacroRegistry::new()).prefix_builtins(true);
+        assert_eq!(expander.builtin_name(Builtin::Gnu), "m4___gnu__");
+        assert_eq!(expander.builtin("m4___gnu__"), Some(Builtin::Gnu));
+        assert_eq!(expander.builtin("__gnu__"), None);
+
+        let result = expander
+            .expand("m4_ifdef(`m4___gnu__', `gnu', `other') m4_ifdef(`__gnu__', `gnu', `other')")
+            .unwrap();
+        assert_eq!(result, "gnu other");
+    }
+
+    #[test]
+    fn test_prefixed_dnl() {
+        let mut expander = Expander::new(MacroRegistry::new()).prefix_builtins(true);
+        let result = expander
+            .expand("hello m4_dnl this is removed\nworld dnl kept\n")
+            .unwrap();
+        assert_eq!(result, "hello world dnl kept\n");
+    }
 }
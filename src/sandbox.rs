@@ -0,0 +1,193 @@
+//! Restricting what input may do outside the expander.
+//!
+//! A [`SandboxPolicy`] set with
+//! [`Expander::sandbox`](crate::processor::Expander::sandbox) turns off
+//! categories of builtins and confines the files builtins read to one
+//! directory. Calls that break the policy fail with
+//! [`M4Error::PolicyViolation`](crate::error::M4Error::PolicyViolation).
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::vfs::FileProvider;
+
+/// Kind of access to the world outside the expander that a builtin needs.
+///
+/// This crate has no builtins that run programs, create temporary files or
+/// write files, like GNU `syscmd`, `mkstemp` and `debugfile`, so there are
+/// no categories for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuiltinCategory {
+    /// Reading files: `include`, `sinclude` and BSD `paste` and `spaste`
+    FileInclusion,
+    /// Stopping expansion with `m4exit`
+    Exit,
+}
+
+impl BuiltinCategory {
+    /// Every category
+    pub const ALL: &'static [BuiltinCategory] =
+        &[BuiltinCategory::FileInclusion, BuiltinCategory::Exit];
+}
+
+impl fmt::Display for BuiltinCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BuiltinCategory::FileInclusion => "file inclusion",
+            BuiltinCategory::Exit => "exiting",
+        })
+    }
+}
+
+/// Which builtins may run and which files they may read. The default allows
+/// everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    disabled: HashSet<BuiltinCategory>,
+    root: Option<PathBuf>,
+}
+
+impl SandboxPolicy {
+    /// A policy that allows everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy for input that is not trusted, like BSD m4's safe mode:
+    /// every category of builtin reaching outside the expander is disabled
+    pub fn safer() -> Self {
+        Self {
+            disabled: BuiltinCategory::ALL.iter().copied().collect(),
+            root: None,
+        }
+    }
+
+    /// Allow or disallow the builtins of `category`
+    pub fn allow(mut self, category: BuiltinCategory, allowed: bool) -> Self {
+        if allowed {
+            self.disabled.remove(&category);
+        } else {
+            self.disabled.insert(category);
+        }
+        self
+    }
+
+    /// Whether builtins of `category` may run
+    pub fn allows(&self, category: BuiltinCategory) -> bool {
+        !self.disabled.contains(&category)
+    }
+
+    /// Only let builtins read files inside `root`. Relative names are taken
+    /// relative to it, and names that lead out of it through `..` or
+    /// symbolic links are rejected.
+    pub fn file_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Directory files are confined to, if any
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Where a builtin asking for the file `name` may read it from `files`.
+    ///
+    /// Fails with [`Access::Outside`] if the file is not inside the root.
+    pub fn resolve(&self, name: &str, files: &dyn FileProvider) -> Result<PathBuf, Access> {
+        let Some(root) = &self.root else {
+            return Ok(PathBuf::from(name));
+        };
+        // Resolving both sides removes `..` and symbolic links
+        let root = files.canonicalize(root).map_err(Access::Io)?;
+        let path = files.canonicalize(&root.join(name)).map_err(Access::Io)?;
+        if path.starts_with(&root) {
+            Ok(path)
+        } else {
+            Err(Access::Outside)
+        }
+    }
+}
+
+/// Why [`SandboxPolicy::resolve`] refused a file
+#[derive(Debug)]
+pub enum Access {
+    /// The file is outside the root
+    Outside,
+    /// The file or the root could not be looked up
+    Io(io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{MemoryFs, RealFs};
+
+    #[test]
+    fn test_resolve() {
+        let root = std::env::temp_dir().join(format!("m4rs-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/inside.m4"), "in").unwrap();
+        std::fs::write(root.with_extension("m4"), "out").unwrap();
+
+        let policy = SandboxPolicy::new().file_root(&root);
+        let real = root.canonicalize().unwrap();
+        assert_eq!(
+            policy.resolve("sub/../sub/inside.m4", &RealFs).unwrap(),
+            real.join("sub/inside.m4")
+        );
+        let absolute = root.with_extension("m4");
+        let outside = format!("../{}", absolute.file_name().unwrap().to_str().unwrap());
+        assert!(matches!(
+            policy.resolve(&outside, &RealFs),
+            Err(Access::Outside)
+        ));
+        assert!(matches!(
+            policy.resolve(absolute.to_str().unwrap(), &RealFs),
+            Err(Access::Outside)
+        ));
+        assert!(matches!(
+            policy.resolve("missing", &RealFs),
+            Err(Access::Io(_))
+        ));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&absolute, root.join("link.m4")).unwrap();
+            assert!(matches!(
+                policy.resolve("link.m4", &RealFs),
+                Err(Access::Outside)
+            ));
+        }
+
+        // Without a root names are used as they are
+        assert_eq!(
+            SandboxPolicy::new().resolve("../x", &RealFs).unwrap(),
+            PathBuf::from("../x")
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(root.with_extension("m4")).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_in_memory() {
+        let files = MemoryFs::new().with_file("tpl/a.m4", "");
+        let policy = SandboxPolicy::new().file_root("tpl");
+        assert_eq!(
+            policy.resolve("./a.m4", &files).unwrap(),
+            PathBuf::from("tpl/a.m4")
+        );
+        assert!(matches!(
+            policy.resolve("../tpl2/a.m4", &files),
+            Err(Access::Outside)
+        ));
+    }
+
+    #[test]
+    fn test_categories() {
+        let policy = SandboxPolicy::safer().allow(BuiltinCategory::FileInclusion, true);
+        assert!(policy.allows(BuiltinCategory::FileInclusion));
+        assert!(!policy.allows(BuiltinCategory::Exit));
+        assert!(SandboxPolicy::default().allows(BuiltinCategory::Exit));
+    }
+}
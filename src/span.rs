@@ -0,0 +1,56 @@
+//! Positions in m4 source text.
+
+use std::fmt;
+
+/// Identifies the source a position points into. What each number stands
+/// for (usually a file name) is up to the caller; inputs that were never
+/// given one use the default, `FileId(0)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FileId(pub u32);
+
+/// Position in a source text. Lines and columns count from 1; columns count
+/// characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Location {
+    /// Byte offset from the start of the source
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    /// Position of the first character of a source
+    pub const START: Location = Location {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+
+    /// Position just after `text`, which starts at this position
+    pub fn advance(mut self, text: &str) -> Location {
+        for c in text.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.offset += text.len();
+        self
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Range of a source text, from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub start: Location,
+    pub end: Location,
+}
@@ -0,0 +1,61 @@
+//! Reports of macro calls, as turned on by `traceon`.
+//!
+//! The expander reports each call of a traced macro to a [`TraceSink`] set
+//! with [`Expander::trace_sink`](crate::processor::Expander::trace_sink),
+//! once the call has run. Input turns tracing on and off with `traceon` and
+//! `traceoff`; [`Expander::trace`](crate::processor::Expander::trace) does
+//! the same from Rust.
+
+use std::fmt;
+
+use crate::span::{FileId, Location};
+
+/// A call of a traced macro
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    /// Calls in progress, this one included
+    pub depth: usize,
+    pub name: String,
+    /// Arguments of the call; empty if it had no parentheses
+    pub arguments: Vec<String>,
+    /// Text the call expanded to, to be rescanned
+    pub expansion: String,
+    /// Input the call was read from
+    pub file: FileId,
+    /// Where the call's name was read
+    pub location: Location,
+}
+
+/// Displays the call as GNU m4 traces it by default, without the leading
+/// `m4trace:`: ``-1- name(`a', `b') -> `expansion'``. An empty expansion
+/// is left out.
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "-{}- {}", self.depth, self.name)?;
+        if !self.arguments.is_empty() {
+            f.write_str("(")?;
+            for (i, arg) in self.arguments.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "`{}'", arg)?;
+            }
+            f.write_str(")")?;
+        }
+        if !self.expansion.is_empty() {
+            write!(f, " -> `{}'", self.expansion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the traced calls of an expander
+pub trait TraceSink {
+    fn trace(&mut self, trace: Trace);
+}
+
+impl<F: FnMut(Trace)> TraceSink for F {
+    fn trace(&mut self, trace: Trace) {
+        self(trace)
+    }
+}
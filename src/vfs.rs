@@ -0,0 +1,311 @@
+//! Where builtins and macro loading get files from.
+//!
+//! All file access goes through a [`FileProvider`]: [`RealFs`] reads the
+//! disk, [`MemoryFs`] serves files from a map, which suits tests and
+//! generated libraries, and [`OverlayFs`] layers such a map over the disk.
+//! [`IncludeFs`] searches an include path in any of them. Set one with
+//! [`Expander::file_provider`](crate::processor::Expander::file_provider).
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Source of the files input asks for
+pub trait FileProvider {
+    /// Read the whole file at `path`
+    fn read(&self, path: &Path) -> io::Result<String>;
+
+    /// The path `path` refers to with `.`, `..` and, where the provider has
+    /// them, symbolic links resolved. Used to keep files inside a
+    /// [`SandboxPolicy`](crate::sandbox::SandboxPolicy) root.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Whether there is a file at `path`
+    fn exists(&self, path: &Path) -> bool {
+        self.read(path).is_ok()
+    }
+}
+
+/// The real filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl FileProvider for RealFs {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+}
+
+/// Files held in memory, keyed by path. Paths are compared after removing
+/// `.` and `..`, so `lib/../a.m4` names the file added as `a.m4`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file, replacing any file at the same path
+    pub fn insert(&mut self, path: impl AsRef<Path>, contents: impl Into<String>) {
+        self.files.insert(normalize(path.as_ref()), contents.into());
+    }
+
+    /// Add a file, builder style
+    pub fn with_file(mut self, path: impl AsRef<Path>, contents: impl Into<String>) -> Self {
+        self.insert(path, contents);
+        self
+    }
+
+    /// Whether a file exists at `path`
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+}
+
+impl FileProvider for MemoryFs {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file in memory"))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(normalize(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.contains(path)
+    }
+}
+
+/// Files in memory over the real filesystem: a file in memory hides a file
+/// on disk at the same path.
+///
+/// Both layers share the disk's namespace. Files in memory are keyed by
+/// their [`OverlayFs::canonicalize`]d path, with relative paths taken from
+/// the current directory when the overlay is created, so a
+/// [`SandboxPolicy`](crate::sandbox::SandboxPolicy) root covers the files
+/// of both layers under it.
+#[derive(Debug, Clone, Default)]
+pub struct OverlayFs {
+    upper: MemoryFs,
+}
+
+impl OverlayFs {
+    pub fn new(upper: MemoryFs) -> Self {
+        let files = upper
+            .files
+            .into_iter()
+            .map(|(path, contents)| (resolve_existing(&path), contents))
+            .collect();
+        Self {
+            upper: MemoryFs { files },
+        }
+    }
+
+    fn upper(&self, path: &Path) -> Option<&String> {
+        self.upper.files.get(&resolve_existing(path))
+    }
+}
+
+impl FileProvider for OverlayFs {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        match self.upper(path) {
+            Some(contents) => Ok(contents.clone()),
+            None => RealFs.read(path),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        if self.upper(path).is_none()
+            && let Ok(real) = RealFs.canonicalize(path)
+        {
+            return Ok(real);
+        }
+        // Files in memory, and directories that exist only as their parents
+        Ok(resolve_existing(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.upper(path).is_some() || RealFs.exists(path)
+    }
+}
+
+/// `path` made absolute, with `.` and `..` removed and symbolic links
+/// resolved in as much of it as exists on disk
+fn resolve_existing(path: &Path) -> PathBuf {
+    let Ok(absolute) = std::path::absolute(path) else {
+        return normalize(path);
+    };
+    let absolute = normalize(&absolute);
+    let mut existing = absolute.as_path();
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(real) = existing.canonicalize() {
+            return missing
+                .iter()
+                .rev()
+                .fold(real, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return absolute,
+        }
+    }
+}
+
+/// Files of another provider looked up along an include path, like GNU m4's
+/// `-I`: a relative path that names no file is looked for in each directory
+/// in turn
+#[derive(Debug, Clone, Default)]
+pub struct IncludeFs<P> {
+    inner: P,
+    dirs: Vec<PathBuf>,
+}
+
+impl<P: FileProvider> IncludeFs<P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Search `dir` after the directories added before it
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dirs.push(dir.into());
+        self
+    }
+
+    /// The file `path` names: `path` itself if it exists or is absolute,
+    /// or else the first match in the include path
+    pub fn locate(&self, path: &Path) -> PathBuf {
+        if self.inner.exists(path) || path.is_absolute() {
+            return path.to_path_buf();
+        }
+        self.dirs
+            .iter()
+            .map(|dir| dir.join(path))
+            .find(|candidate| self.inner.exists(candidate))
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+impl<P: FileProvider> FileProvider for IncludeFs<P> {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        self.inner.read(&self.locate(path))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(&self.locate(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(&self.locate(path))
+    }
+}
+
+/// Remove `.` and `..` from `path` without looking at any filesystem. A
+/// `..` at the start of a relative path is kept.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                let at_start = matches!(
+                    normal.components().next_back(),
+                    None | Some(Component::ParentDir)
+                );
+                if at_start {
+                    normal.push("..");
+                } else {
+                    normal.pop();
+                }
+            }
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory() {
+        let files = MemoryFs::new().with_file("lib/a.m4", "A");
+        assert_eq!(files.read(Path::new("./lib/x/../a.m4")).unwrap(), "A");
+        let error = files.read(Path::new("a.m4")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(normalize(Path::new("../x/../../y")), Path::new("../../y"));
+        assert_eq!(normalize(Path::new("/a/../../b")), Path::new("/b"));
+    }
+
+    #[test]
+    fn test_include_path() {
+        let files = MemoryFs::new()
+            .with_file("a.m4", "top")
+            .with_file("inc/a.m4", "inc")
+            .with_file("inc/b.m4", "b")
+            .with_file("lib/b.m4", "lib b")
+            .with_file("lib/c.m4", "c");
+        let files = IncludeFs::new(files).with_dir("inc").with_dir("lib");
+        assert_eq!(files.read(Path::new("a.m4")).unwrap(), "top");
+        assert_eq!(files.read(Path::new("b.m4")).unwrap(), "b");
+        assert_eq!(files.read(Path::new("c.m4")).unwrap(), "c");
+        assert_eq!(files.locate(Path::new("c.m4")), Path::new("lib/c.m4"));
+        assert!(!files.exists(Path::new("d.m4")));
+        assert!(files.read(Path::new("/c.m4")).is_err());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        let fake = Path::new(env!("CARGO_MANIFEST_DIR")).join("fake.m4");
+        let files = OverlayFs::new(MemoryFs::new().with_file(&fake, "fake"));
+        assert_eq!(files.read(&fake).unwrap(), "fake");
+        assert!(files.read(&manifest).unwrap().contains("[package]"));
+        assert_eq!(files.canonicalize(&fake).unwrap(), fake);
+    }
+
+    #[test]
+    fn test_overlay_sandbox() {
+        use crate::sandbox::{Access, SandboxPolicy};
+
+        // Tests run in the package root, where `src` is on disk
+        let files = OverlayFs::new(MemoryFs::new().with_file("src/generated.m4", "generated"));
+        let policy = SandboxPolicy::new().file_root("src");
+        let path = policy.resolve("generated.m4", &files).unwrap();
+        assert!(path.is_absolute());
+        assert_eq!(files.read(&path).unwrap(), "generated");
+        let path = policy.resolve("vfs.rs", &files).unwrap();
+        assert!(files.read(&path).unwrap().contains("struct OverlayFs"));
+        assert!(matches!(
+            policy.resolve("../Cargo.toml", &files),
+            Err(Access::Outside)
+        ));
+
+        // A root that exists only in memory
+        let files = OverlayFs::new(MemoryFs::new().with_file("virtual/a.m4", "A"));
+        let policy = SandboxPolicy::new().file_root("virtual");
+        let path = policy.resolve("a.m4", &files).unwrap();
+        assert_eq!(files.read(&path).unwrap(), "A");
+    }
+}
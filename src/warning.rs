@@ -0,0 +1,80 @@
+//! Warnings about questionable input that expansion accepts anyway.
+//!
+//! The expander reports warnings to a [`WarningSink`] set with
+//! [`Expander::warning_sink`](crate::processor::Expander::warning_sink).
+//! Each warning has a [`WarningKind`] that can be turned off on its own, and
+//! [`Expander::fatal_warnings`](crate::processor::Expander::fatal_warnings)
+//! makes any warning stop expansion with
+//! [`M4Error::FatalWarning`](crate::error::M4Error::FatalWarning).
+
+use std::fmt;
+
+use crate::error::CallSite;
+use crate::span::{FileId, Location};
+
+/// What a warning is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    /// A builtin was called with more arguments than it uses; the extra
+    /// ones are ignored
+    ExcessArguments,
+    /// A builtin was called with fewer arguments than it needs, and did
+    /// nothing or treated the missing ones as empty
+    TooFewArguments,
+    /// A builtin expecting a number got something else
+    NonNumeric,
+}
+
+impl WarningKind {
+    /// Every kind of warning
+    pub const ALL: &'static [WarningKind] = &[
+        WarningKind::ExcessArguments,
+        WarningKind::TooFewArguments,
+        WarningKind::NonNumeric,
+    ];
+
+    /// Short name of the kind, such as `excess-arguments`
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningKind::ExcessArguments => "excess-arguments",
+            WarningKind::TooFewArguments => "too-few-arguments",
+            WarningKind::NonNumeric => "non-numeric",
+        }
+    }
+
+    /// Look up a kind by its short name
+    pub fn from_name(name: &str) -> Option<WarningKind> {
+        WarningKind::ALL.iter().copied().find(|k| k.name() == name)
+    }
+}
+
+/// Questionable input found during expansion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+    /// Input the call that issued the warning was read from
+    pub file: FileId,
+    /// Where in the input the call that issued the warning was read
+    pub location: Location,
+    /// Macro calls in progress, outermost first; the last one issued the
+    /// warning
+    pub stack: Vec<CallSite>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Receives the warnings of an expander
+pub trait WarningSink {
+    fn warn(&mut self, warning: Warning);
+}
+
+impl<F: FnMut(Warning)> WarningSink for F {
+    fn warn(&mut self, warning: Warning) {
+        self(warning)
+    }
+}
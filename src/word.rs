@@ -0,0 +1,192 @@
+//! Which text the expander takes as macro names.
+//!
+//! By default a word is a letter or `_` followed by letters, digits and `_`.
+//! A [`WordSyntax`] replaces that with a regular expression, like GNU m4's
+//! `changeword`: at each position the expander reads the longest text the
+//! expression matches there as one word. If the expression has a capture
+//! group, the text of the first group is the macro name; a word that does
+//! not name a macro is output whole.
+//!
+//! Set one with
+//! [`Expander::word_syntax`](crate::processor::Expander::word_syntax) or
+//! from input with `changeword`.
+
+use regex_automata::hybrid::LazyStateID;
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::meta::Regex;
+use regex_automata::util::start;
+use regex_automata::{Anchored, Input};
+use thiserror::Error;
+
+/// Expression matching the words recognized by default
+pub const DEFAULT_WORD: &str = "[_a-zA-Z][_a-zA-Z0-9]*";
+
+/// A regular expression that cannot be used as a word syntax
+#[derive(Debug, Error)]
+#[error("invalid word syntax `{pattern}': {message}")]
+pub struct InvalidWordSyntax {
+    pub pattern: String,
+    pub message: String,
+}
+
+/// Regular expression words must match, in the syntax of Rust's `regex`
+/// crate
+#[derive(Debug, Clone)]
+pub struct WordSyntax {
+    pattern: String,
+    /// Finds where words end in input read a character at a time
+    dfa: DFA,
+    cache: Cache,
+    /// Finds the macro name inside a word
+    regex: Regex,
+}
+
+impl WordSyntax {
+    pub fn new(pattern: &str) -> Result<Self, InvalidWordSyntax> {
+        let invalid = |message: String| InvalidWordSyntax {
+            pattern: pattern.to_owned(),
+            message,
+        };
+        let dfa = DFA::new(pattern).map_err(|e| invalid(e.to_string()))?;
+        let regex = Regex::new(pattern).map_err(|e| invalid(e.to_string()))?;
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            cache: dfa.create_cache(),
+            dfa,
+            regex,
+        })
+    }
+
+    /// The regular expression
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether this is the syntax the expander uses by default
+    pub fn is_default(&self) -> bool {
+        self.pattern == DEFAULT_WORD
+    }
+
+    /// The macro name in `word`: the first capture group if there is one,
+    /// or else the whole word
+    pub fn macro_name<'a>(&self, word: &'a str) -> &'a str {
+        let mut captures = self.regex.create_captures();
+        self.regex
+            .captures(Input::new(word).anchored(Anchored::Yes), &mut captures);
+        match captures.get_group(1) {
+            Some(span) => &word[span],
+            None => word,
+        }
+    }
+
+    /// Length of the word at the start of `text`, if there is one
+    pub fn word_len(&mut self, text: &str) -> Option<usize> {
+        let mut scan = self.start();
+        for c in text.chars() {
+            if !self.push(&mut scan, c) {
+                return scan.matched;
+            }
+        }
+        self.finish(&mut scan);
+        scan.matched
+    }
+
+    /// Whether a word can start with `c`
+    pub(crate) fn can_start(&mut self, c: char) -> bool {
+        let mut scan = self.start();
+        self.push(&mut scan, c)
+    }
+
+    /// Start looking for a word
+    pub(crate) fn start(&mut self) -> WordScan {
+        let config = start::Config::new().anchored(Anchored::Yes);
+        let state = self.dfa.start_state(&mut self.cache, &config).ok();
+        WordScan {
+            state,
+            len: 0,
+            matched: None,
+        }
+    }
+
+    /// Look at the next character. Returns `false` once no longer word can
+    /// be found, which leaves `c` out of the word.
+    pub(crate) fn push(&mut self, scan: &mut WordScan, c: char) -> bool {
+        let mut bytes = [0; 4];
+        for (i, &byte) in c.encode_utf8(&mut bytes).as_bytes().iter().enumerate() {
+            let state = scan
+                .state
+                .and_then(|state| self.dfa.next_state(&mut self.cache, state, byte).ok());
+            // Matches show one byte late: this one is of the text before `c`
+            if i == 0 && scan.len > 0 && state.is_some_and(|s| s.is_match()) {
+                scan.matched = Some(scan.len);
+            }
+            scan.state = state.filter(|s| !s.is_dead() && !s.is_quit());
+            if scan.state.is_none() {
+                return false;
+            }
+        }
+        scan.len += c.len_utf8();
+        true
+    }
+
+    /// The input ended after the characters pushed
+    pub(crate) fn finish(&mut self, scan: &mut WordScan) {
+        let state = scan
+            .state
+            .and_then(|state| self.dfa.next_eoi_state(&mut self.cache, state).ok());
+        if scan.len > 0 && state.is_some_and(|s| s.is_match()) {
+            scan.matched = Some(scan.len);
+        }
+        scan.state = None;
+    }
+}
+
+impl Default for WordSyntax {
+    fn default() -> Self {
+        Self::new(DEFAULT_WORD).expect("the default word syntax is valid")
+    }
+}
+
+/// Progress of looking for a word in text read a character at a time
+pub(crate) struct WordScan {
+    /// `None` once the expression cannot match more text
+    state: Option<LazyStateID>,
+    /// Bytes looked at
+    len: usize,
+    /// Length in bytes of the longest word found so far. Words are never
+    /// empty.
+    matched: Option<usize>,
+}
+
+impl WordScan {
+    pub(crate) fn matched(&self) -> Option<usize> {
+        self.matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_len() {
+        let mut words = WordSyntax::new("@([A-Z_]+)@").unwrap();
+        assert_eq!(words.word_len("@NAME@ rest"), Some(6));
+        assert_eq!(words.word_len("@NAME rest"), None);
+        assert_eq!(words.word_len("name"), None);
+        assert!(words.can_start('@') && !words.can_start('N'));
+        assert_eq!(words.macro_name("@NAME@"), "NAME");
+
+        let mut default = WordSyntax::default();
+        assert!(default.is_default());
+        assert_eq!(default.word_len("foo_1(x)"), Some(5));
+        assert_eq!(default.word_len("1foo"), None);
+        assert_eq!(default.macro_name("foo"), "foo");
+
+        // Words are never empty
+        let mut optional = WordSyntax::new("x*").unwrap();
+        assert_eq!(optional.word_len("y"), None);
+        assert_eq!(optional.word_len("xxy"), Some(2));
+        assert!(WordSyntax::new("(").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+//! Runs the `m4rs` binary on files in a temporary directory.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A fresh directory for one test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("m4rs-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_include_path() {
+    let dir = temp_dir("include");
+    fs::create_dir_all(dir.join("inc")).unwrap();
+    fs::write(dir.join("inc/lib.m4"), "define(`greet', `Hello, $1!')dnl\n").unwrap();
+    fs::write(dir.join("inc/top.m4"), "top\n").unwrap();
+    fs::write(
+        dir.join("main.m4"),
+        "include(`lib.m4')greet(`World')\nsinclude(`missing.m4')done\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_m4rs"))
+        .current_dir(&dir)
+        .args(["-I", "inc", "main.m4", "top.m4"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Hello, World!\ndone\ntop\n"
+    );
+
+    // Without the include path the file is not found
+    let output = Command::new(env!("CARGO_BIN_EXE_m4rs"))
+        .current_dir(&dir)
+        .arg("main.m4")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot open `lib.m4'"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_error_in_included_file() {
+    let dir = temp_dir("included-error");
+    fs::write(dir.join("lib.m4"), "ok\n  `open\n").unwrap();
+    fs::write(dir.join("main.m4"), "one\ninclude(`lib.m4')\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_m4rs"))
+        .current_dir(&dir)
+        .arg("main.m4")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(" --> lib.m4:2:3\n"), "{}", stderr);
+    assert!(stderr.contains("2 |   `open\n"), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wrap_and_trace() {
+    let dir = temp_dir("wrap-trace");
+    fs::write(
+        dir.join("one.m4"),
+        "m4wrap(`f(`wrapped')')define(`f', `[$1]')dnl\n",
+    )
+    .unwrap();
+    fs::write(dir.join("two.m4"), "f(`two')\n").unwrap();
+
+    // Text saved in one file is read after the last
+    let output = Command::new(env!("CARGO_BIN_EXE_m4rs"))
+        .current_dir(&dir)
+        .args(["-tf", "-o", "trace.log", "one.m4", "two.m4"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[two]\n[wrapped]"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("trace.log")).unwrap(),
+        "m4trace: -1- f(`two') -> `[two]'\nm4trace: -1- f(`wrapped') -> `[wrapped]'\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_reload_gnu_frozen_file() {
+    let frozen = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/gnu-1.4.19.m4f");
+    let output = Command::new(env!("CARGO_BIN_EXE_m4rs"))
+        .arg("-R")
+        .arg(&frozen)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            child.stdin.take().unwrap().write_all(b"foo(x)\n")?;
+            child.wait_with_output()
+        })
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "bar x\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("warning: ") && stderr.contains("builtin `format' is not supported"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,170 @@
+//! Examples from the GNU m4 manual, with the output GNU m4 gives for them,
+//! expanded in the GNU dialect.
+
+use m4rs::processor::{Dialect, Expander, MacroRegistry};
+
+/// Section of the manual, input and expected output. Each input is expanded
+/// by a fresh expander.
+const EXAMPLES: &[(&str, &str, &str)] = &[
+    (
+        "Define",
+        "define(`foo', `Hello world.')\nfoo\n",
+        "\nHello world.\n",
+    ),
+    (
+        "Define",
+        "define(`foo')\n`'foo`'.\ndefine\n",
+        "\n.\ndefine\n",
+    ),
+    (
+        "Arguments",
+        "define(`exch', `$2, $1')\nexch(`arg1', `arg2')\n",
+        "\narg2, arg1\n",
+    ),
+    (
+        "Arguments",
+        "define(`exch', `$2, $1')\ndefine(exch(``expansion text'', ``macro''))\nmacro\n",
+        "\n\nexpansion text\n",
+    ),
+    (
+        "Arguments",
+        "define(`test', ``Macro name: $0'')\ntest\n",
+        "\nMacro name: test\n",
+    ),
+    (
+        "Arguments",
+        "define(`foo', `This is macro `foo'.')\nfoo\n",
+        "\nThis is macro foo.\n",
+    ),
+    (
+        "Pseudo Arguments",
+        "define(`nargs', `$#')\nnargs\nnargs()\nnargs(`arg1', `arg2', `arg3')\n\
+         nargs(`commas can be quoted, like this')\n\
+         nargs(arg1#inside comments, commas do not separate arguments\nstill arg1)\n\
+         nargs((unquoted parentheses, like this, group arguments))\n",
+        "\n0\n1\n3\n1\n1\n1\n",
+    ),
+    (
+        "Pseudo Arguments",
+        "define(`echo', `$*')\necho(arg1,    arg2, arg3 , arg4)\n",
+        "\narg1,arg2,arg3 ,arg4\n",
+    ),
+    (
+        "Pseudo Arguments",
+        "define(`echo', `$@')\necho(arg1,    arg2, arg3 , arg4)\n",
+        "\narg1,arg2,arg3 ,arg4\n",
+    ),
+    (
+        "Pseudo Arguments",
+        "define(`echo1', `$*')\ndefine(`echo2', `$@')\ndefine(`foo', `This is macro `foo'.')\n\
+         echo1(foo)\necho1(`foo')\necho2(foo)\necho2(`foo')\n",
+        "\n\n\nThis is macro This is macro foo..\nThis is macro foo.\nThis is macro foo.\nfoo\n",
+    ),
+    (
+        "Macro Arguments",
+        "define(`macro', `$1')\nmacro( unquoted leading space lost)\n\
+         macro(` quoted leading space kept')\n\
+         macro(macro(`\n')`whitespace from expansion kept')\n\
+         macro(`unquoted trailing whitespace kept'\n)\n",
+        "\nunquoted leading space lost\n quoted leading space kept\n\
+         \nwhitespace from expansion kept\nunquoted trailing whitespace kept\n\n",
+    ),
+    (
+        "Quoting Arguments",
+        "define(`active', `ACT, IVE')\ndefine(`show', `$1 $1')\n\
+         show(active)\nshow(`active')\nshow(``active'')\n",
+        "\n\nACT ACT\nACT, IVE ACT, IVE\nactive active\n",
+    ),
+    (
+        "Quoting Arguments",
+        "define(`foo', `expansion text')\n`foo'\n``foo''\n",
+        "\nfoo\n`foo'\n",
+    ),
+    (
+        "Ifdef",
+        "ifdef(`foo', ``foo' is defined', ``foo' is not defined')\ndefine(`foo', `')\n\
+         ifdef(`foo', ``foo' is defined', ``foo' is not defined')\n\
+         ifdef(`no_such_macro', `yes', `no', `extra argument')\n",
+        "foo is not defined\n\nfoo is defined\nno\n",
+    ),
+    (
+        "Ifelse",
+        "ifelse(`some comments')\nifelse(`foo', `bar')\n",
+        "\n\n",
+    ),
+    (
+        "Ifelse",
+        "ifelse(`foo', `bar', `true')\nifelse(`foo', `foo', `true')\ndefine(`foo', `bar')\n\
+         ifelse(foo, `bar', `true', `false')\nifelse(foo, `foo', `true', `false')\n",
+        "\ntrue\n\ntrue\nfalse\n",
+    ),
+    (
+        "Ifelse",
+        "ifelse(`foo', `bar', `third', `gnu', `gnats', `sixth', `seventh')\n\
+         ifelse(`foo', `bar', `third', `gnu', `gnats', `sixth')\n\
+         ifelse(`foo', `bar', `3', `gnu', `gnats', `6', `7', `8')\n",
+        "seventh\n\n7\n",
+    ),
+    (
+        "Ifelse",
+        "ifelse(` a', `a', `exact', `trimmed')\nifelse(`a', `a', ` kept ')\n",
+        "trimmed\n kept \n",
+    ),
+    (
+        "Dnl",
+        "define(`foo', `Macro `foo'.')dnl A very simple macro, indeed.\nfoo\n",
+        "Macro foo.\n",
+    ),
+    (
+        "Comments",
+        "define(`comment', `COMMENT')\n# A comment\ncomment # comment\n",
+        "\n# A comment\nCOMMENT # comment\n",
+    ),
+    (
+        "Builtin",
+        "builtin(`define', `x', `y')x\nbuiltin\n",
+        "y\nbuiltin\n",
+    ),
+    (
+        "Builtin",
+        "define(`dnl', `X')dnl\ndefine(`ifdef', `Y')ifdef(`a', `b')\nbuiltin(`ifdef', `dnl', `def')\n",
+        "X\nY\ndef\n",
+    ),
+    (
+        "Location",
+        "define(`where', ``$0' called at __file__:__line__')\nwhere\n",
+        "\nwhere called at stdin:2\n",
+    ),
+    (
+        "M4wrap",
+        "define(`cleanup', `This is the `cleanup' action.\n')\nm4wrap(`cleanup()')\n\
+         This is the first and last normal input line.\n",
+        "\n\nThis is the first and last normal input line.\nThis is the cleanup action.\n",
+    ),
+    (
+        "Eval",
+        "eval(`-3 * 5')\neval(`-99 / 10')\neval(`-99 % 10')\neval(`99 % -10')\n",
+        "-15\n-9\n-9\n9\n",
+    ),
+    (
+        "Eval",
+        "eval(`666', `10')\neval(`666', `11')\neval(`666', `6')\neval(`666', `6', `10')\n\
+         eval(`-666', `6', `10')\neval(`10', `', `0')\n`0r1:'eval(`10', `1', `11')\n\
+         eval(`10', `16')\n",
+        "666\n556\n3030\n0000003030\n-0000003030\n10\n0r1:01111111111\na\n",
+    ),
+    (
+        "Macro Invocation",
+        "printf(`%d', x)\nfoo (bar)\n",
+        "printf(%d, x)\nfoo (bar)\n",
+    ),
+];
+
+#[test]
+fn test_gnu_manual() {
+    for (section, input, expected) in EXAMPLES {
+        let mut expander = Expander::new(MacroRegistry::new()).dialect(Dialect::Gnu);
+        let output = expander.expand(input).unwrap();
+        assert_eq!(output, *expected, "{} example:\n{}", section, input);
+    }
+}